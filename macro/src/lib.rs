@@ -2,10 +2,11 @@ use proc_macro::TokenStream;
 use syn::{parse_macro_input, DeriveInput, Error};
 
 #[proc_macro_attribute]
-pub fn job_type(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn job_type(attr: TokenStream, item: TokenStream) -> TokenStream {
     let input = parse_macro_input!(item as DeriveInput);
+    let attrs = parse_macro_input!(attr as args::Args);
 
-    job_type_macro::expand(input)
+    job_type_macro::expand(attrs, input)
         .unwrap_or_else(Error::into_compile_error)
         .into()
 }
@@ -20,6 +21,18 @@ pub fn job(attr: TokenStream, item: TokenStream) -> TokenStream {
         .into()
 }
 
+/// Register a function upgrading a job's stored payload from version `from` to `from + 1`, e.g.
+/// `#[job_migration(MyJob, from = 1)]`
+#[proc_macro_attribute]
+pub fn job_migration(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as syn::ItemFn);
+    let attrs = parse_macro_input!(attr as job_migration_macro::MigrationAttrs);
+
+    job_migration_macro::expand(attrs, input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
 // Re-export typetag macros
 use quote::format_ident;
 use typetag_impl::{Mode, expand, get_crate_path};
@@ -49,16 +62,22 @@ pub fn deserialize(args: TokenStream, input: TokenStream) -> TokenStream {
 mod job_type_macro {
     use proc_macro2::TokenStream;
     use quote::{quote, format_ident};
-    use syn::{Data, DeriveInput, Result};
+    use syn::{Data, DeriveInput, Error, Result};
+
+    use crate::args::Args;
 
-    pub(crate) fn expand(input: DeriveInput) -> Result<TokenStream> {
+    pub(crate) fn expand(args: Args, input: DeriveInput) -> Result<TokenStream> {
         let visibility = input.vis;
         let name = input.ident;
         let trait_name = format_ident!("{}Marker", name);
         let attrs = input.attrs;
 
-        // TODO - Make configurable with attr
-        let job_type_str = name.to_string();
+        if let Some(version) = args.version {
+            return Err(Error::new(version.span(), "Versions are set on jobs, not job types"));
+        }
+
+        let job_type_str = args.name.map_or_else(|| name.to_string(), |name| name.value());
+        let aliases = args.aliases;
 
         let fields = if let Data::Struct(x) = input.data {
             x.fields
@@ -74,14 +93,23 @@ mod job_type_macro {
                 fn job_type() -> String {
                     String::from(#job_type_str)
                 }
+
+                fn job_type_aliases() -> Vec<String> {
+                    vec![#(String::from(#aliases)),*]
+                }
             }
 
             #[::ajobqueue::serde(tag="type")]
             #visibility trait #trait_name: ::ajobqueue::Job<JobTypeData=#name> {
                 fn into_any(self: Box<Self>) -> Box<dyn ::std::any::Any>;
+                fn job_version(&self) -> u32;
             }
 
-            impl ::ajobqueue::JobTypeMarker for dyn #trait_name<JobTypeData=#name> {}
+            impl ::ajobqueue::JobTypeMarker for dyn #trait_name<JobTypeData=#name> {
+                fn job_version(&self) -> u32 {
+                    #trait_name::job_version(self)
+                }
+            }
         };
 
         Ok(expanded)
@@ -91,16 +119,25 @@ mod job_type_macro {
 mod job_macro {
     use proc_macro2::{TokenStream, Ident};
     use quote::{quote, format_ident};
-    use syn::{Data, DeriveInput, Result, parse::Parse};
+    use syn::{Data, DeriveInput, LitInt, Result, Token, parse::Parse};
+
+    use crate::args::Args;
 
     pub struct JobAttrs {
         pub name: Ident,
+        pub args: Args,
     }
 
     impl Parse for JobAttrs {
         fn parse(input: syn::parse::ParseStream) -> Result<Self> {
             let name: Ident = input.parse()?;
-            Ok(JobAttrs { name })
+            let args = if input.is_empty() {
+                Args::default()
+            } else {
+                input.parse::<Token![,]>()?;
+                input.parse()?
+            };
+            Ok(JobAttrs { name, args })
         }
     }
 
@@ -109,7 +146,26 @@ mod job_macro {
         let name = input.ident;
         let sattrs = input.attrs;
 
-        let job_trait_name = format_ident!("{}Marker", attrs.name);
+        let job_type_name = attrs.name;
+        let job_trait_name = format_ident!("{}Marker", job_type_name);
+
+        let job_str = attrs.args.name.as_ref().map_or_else(|| name.to_string(), |name| name.value());
+        let typetag_args = attrs.args.name.map(|name| quote!((name = #name)));
+        let version = attrs.args.version.unwrap_or_else(|| LitInt::new("1", name.span()));
+
+        // Aliases are resolved before deserializing, see `ajobqueue::JOB_ALIASES`
+        let alias_statics = attrs.args.aliases.iter().enumerate().map(|(i, alias)| {
+            let static_name = format_ident!("ALIAS_{}", i);
+            quote! {
+                #[::ajobqueue::linkme::distributed_slice(::ajobqueue::JOB_ALIASES)]
+                #[linkme(crate = ::ajobqueue::linkme)]
+                static #static_name: ::ajobqueue::JobAlias = ::ajobqueue::JobAlias {
+                    job_type: <#job_type_name as ::ajobqueue::JobType>::job_type,
+                    alias: #alias,
+                    name: #job_str,
+                };
+            }
+        });
 
         let fields = if let Data::Struct(x) = input.data {
             x.fields
@@ -122,14 +178,112 @@ mod job_macro {
             #[derive(Clone, Debug, ::ajobqueue::typetag::serde::Serialize, ::ajobqueue::typetag::serde::Deserialize)]
             #visibility struct #name #fields
 
-            #[::ajobqueue::serde]
+            #[::ajobqueue::serde #typetag_args]
             impl #job_trait_name for #name {
                 fn into_any(self: Box<Self>) -> Box<dyn ::std::any::Any> {
                     self
                 }
+
+                fn job_version(&self) -> u32 {
+                    #version
+                }
+            }
+
+            impl ::ajobqueue::NamedJob for #name {
+                type JobType = #job_type_name;
+                const NAME: &'static str = #job_str;
+            }
+
+            const _: () = {
+                #(#alias_statics)*
+            };
+        };
+
+        Ok(expanded)
+    }
+}
+
+mod job_migration_macro {
+    use proc_macro2::TokenStream;
+    use quote::quote;
+    use syn::{parse::Parse, Error, ItemFn, LitInt, Path, Result, Token};
+
+    pub struct MigrationAttrs {
+        pub job: Path,
+        pub from: LitInt,
+    }
+
+    impl Parse for MigrationAttrs {
+        fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+            let job: Path = input.parse()?;
+            input.parse::<Token![,]>()?;
+            let key: syn::Ident = input.parse()?;
+            if key != "from" {
+                return Err(Error::new(key.span(), "Expected from"));
             }
+            input.parse::<Token![=]>()?;
+            let from: LitInt = input.parse()?;
+            Ok(MigrationAttrs { job, from })
+        }
+    }
+
+    pub(crate) fn expand(attrs: MigrationAttrs, input: ItemFn) -> Result<TokenStream> {
+        let job = attrs.job;
+        let from = attrs.from;
+        let fn_name = &input.sig.ident;
+
+        let expanded = quote! {
+            #input
+
+            const _: () = {
+                #[::ajobqueue::linkme::distributed_slice(::ajobqueue::JOB_MIGRATIONS)]
+                #[linkme(crate = ::ajobqueue::linkme)]
+                static MIGRATION: ::ajobqueue::JobMigration = ::ajobqueue::JobMigration {
+                    job_type: <<#job as ::ajobqueue::NamedJob>::JobType as ::ajobqueue::JobType>::job_type,
+                    name: <#job as ::ajobqueue::NamedJob>::NAME,
+                    from: #from,
+                    migrate: #fn_name,
+                };
+            };
         };
 
         Ok(expanded)
     }
 }
+
+mod args {
+    use syn::{parse::{Parse, ParseStream}, Error, LitInt, LitStr, Result, Token};
+
+    /// `name = "..."`, any number of `alias = "..."` and `version = N`, separated by commas
+    #[derive(Default)]
+    pub struct Args {
+        pub name: Option<LitStr>,
+        pub aliases: Vec<LitStr>,
+        pub version: Option<LitInt>,
+    }
+
+    impl Parse for Args {
+        fn parse(input: ParseStream) -> Result<Self> {
+            let mut args = Args::default();
+
+            while !input.is_empty() {
+                let key: syn::Ident = input.parse()?;
+                input.parse::<Token![=]>()?;
+
+                match key.to_string().as_str() {
+                    "name" if args.name.is_none() => args.name = Some(input.parse()?),
+                    "alias" => args.aliases.push(input.parse()?),
+                    "version" if args.version.is_none() => args.version = Some(input.parse()?),
+                    "name" | "version" => return Err(Error::new(key.span(), format!("Duplicate {}", key))),
+                    _ => return Err(Error::new(key.span(), "Expected name, alias or version")),
+                }
+
+                if !input.is_empty() {
+                    input.parse::<Token![,]>()?;
+                }
+            }
+
+            Ok(args)
+        }
+    }
+}
@@ -0,0 +1,18 @@
+ALTER TABLE job_queue
+    ADD COLUMN attempts INT default 0 not null,
+    ADD COLUMN max_attempts INT default 1 not null,
+    ADD COLUMN errors JSONB default '[]' not null;
+
+CREATE TABLE job_dead_letter (
+    id INT primary key generated always as identity,
+    uid UUID unique not null,
+    type VARCHAR not null,
+    data JSONB not null,
+    errors JSONB not null,
+    attempts INT not null,
+    max_attempts INT not null,
+    created TIMESTAMPTZ not null,
+    dead_lettered TIMESTAMPTZ not null
+);
+
+CREATE INDEX job_dead_letter_type ON job_dead_letter (type);
@@ -0,0 +1,18 @@
+CREATE TABLE job_archive (
+    id INT primary key generated always as identity,
+    uid UUID unique not null,
+    type VARCHAR not null,
+    data JSONB not null,
+    result JSONB default null,
+    errors JSONB not null,
+    state JOB_STATE not null,
+    attempts INT not null,
+    max_attempts INT not null,
+    created TIMESTAMPTZ not null,
+    started TIMESTAMPTZ default null,
+    completed TIMESTAMPTZ default null,
+    archived TIMESTAMPTZ not null
+);
+
+CREATE INDEX job_queue_state_completed ON job_queue (state, completed);
+CREATE INDEX job_dead_letter_dead_lettered ON job_dead_letter (dead_lettered);
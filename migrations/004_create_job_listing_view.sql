@@ -0,0 +1,8 @@
+CREATE VIEW job_listing AS
+    SELECT id, uid, type, data, result, state, created, started, completed, attempts, max_attempts, errors
+    FROM job_queue
+    UNION ALL
+    SELECT id, uid, type, data, errors -> -1, 'failed'::job_state, created, NULL, dead_lettered, attempts, max_attempts, errors
+    FROM job_dead_letter;
+
+CREATE INDEX job_queue_created ON job_queue (created);
@@ -0,0 +1,3 @@
+CREATE INDEX job_queue_type_state ON job_queue (type, state);
+CREATE INDEX job_queue_state_type_created ON job_queue (state, type, created);
+CREATE INDEX job_queue_started ON job_queue (started);
@@ -0,0 +1,9 @@
+ALTER TABLE job_queue
+    ADD COLUMN trace_context JSONB default '{}' not null;
+
+CREATE OR REPLACE VIEW job_listing AS
+    SELECT id, uid, type, data, result, state, created, started, completed, attempts, max_attempts, errors, trace_context
+    FROM job_queue
+    UNION ALL
+    SELECT id, uid, type, data, errors -> -1, 'failed'::job_state, created, NULL, dead_lettered, attempts, max_attempts, errors, '{}'::jsonb
+    FROM job_dead_letter;
@@ -0,0 +1,5 @@
+CREATE TABLE job_rate_limit (
+    key VARCHAR primary key,
+    window_start TIMESTAMPTZ not null,
+    count INT not null
+);
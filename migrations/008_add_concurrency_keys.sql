@@ -0,0 +1,19 @@
+ALTER TABLE job_queue
+    ADD COLUMN concurrency_key VARCHAR,
+    ADD COLUMN concurrency_limit INT default 1 not null;
+
+ALTER TABLE job_dead_letter
+    ADD COLUMN concurrency_key VARCHAR,
+    ADD COLUMN concurrency_limit INT default 1 not null;
+
+CREATE INDEX job_queue_running_concurrency_key ON job_queue (concurrency_key)
+    WHERE state = 'running' AND concurrency_key IS NOT NULL;
+
+CREATE OR REPLACE VIEW job_listing AS
+    SELECT id, uid, type, data, result, state, created, started, completed, attempts, max_attempts, errors, trace_context,
+        concurrency_key, concurrency_limit
+    FROM job_queue
+    UNION ALL
+    SELECT id, uid, type, data, errors -> -1, 'failed'::job_state, created, NULL, dead_lettered, attempts, max_attempts, errors, '{}'::jsonb,
+        concurrency_key, concurrency_limit
+    FROM job_dead_letter;
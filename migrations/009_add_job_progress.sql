@@ -0,0 +1,12 @@
+ALTER TABLE job_queue
+    ADD COLUMN progress REAL,
+    ADD COLUMN progress_message VARCHAR;
+
+CREATE OR REPLACE VIEW job_listing AS
+    SELECT id, uid, type, data, result, state, created, started, completed, attempts, max_attempts, errors, trace_context,
+        concurrency_key, concurrency_limit, progress, progress_message
+    FROM job_queue
+    UNION ALL
+    SELECT id, uid, type, data, errors -> -1, 'failed'::job_state, created, NULL, dead_lettered, attempts, max_attempts, errors, '{}'::jsonb,
+        concurrency_key, concurrency_limit, NULL, NULL
+    FROM job_dead_letter;
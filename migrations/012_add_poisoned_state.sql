@@ -0,0 +1 @@
+ALTER TYPE job_state ADD VALUE 'poisoned';
@@ -0,0 +1,11 @@
+CREATE TABLE job_attempt (
+    id INT primary key generated always as identity,
+    job_uid UUID not null,
+    attempt INT not null,
+    started TIMESTAMPTZ not null,
+    finished TIMESTAMPTZ not null,
+    worker_id VARCHAR not null,
+    error JSONB default null
+);
+
+CREATE INDEX job_attempt_job_uid ON job_attempt (job_uid);
@@ -0,0 +1,2 @@
+CREATE INDEX job_queue_pending_concurrency_key ON job_queue (concurrency_key, created)
+    WHERE state = 'not-started' AND concurrency_key IS NOT NULL;
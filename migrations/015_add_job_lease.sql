@@ -0,0 +1,5 @@
+ALTER TABLE job_queue
+    ADD COLUMN lease_expires TIMESTAMPTZ;
+
+CREATE INDEX job_queue_running_lease_expires ON job_queue (lease_expires)
+    WHERE state = 'running';
@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+use ulid::Ulid;
+
+use crate::{
+    error::StorageError,
+    storage::{JobMetadata, JobProgress},
+    AJobQueueError, JobTypeMarker, StorageProvider,
+};
+
+/// The parts of a `StorageProvider` a running job can reach, without the job type parameter
+#[async_trait]
+pub(crate) trait JobStorage: Send {
+    async fn set_progress(&mut self, uid: Ulid, progress: &JobProgress) -> Result<(), StorageError>;
+    async fn save_checkpoint(&mut self, uid: Ulid, checkpoint: &Value) -> Result<(), StorageError>;
+}
+
+#[async_trait]
+impl<J: JobTypeMarker + ?Sized> JobStorage for Box<dyn StorageProvider<J>> {
+    async fn set_progress(&mut self, uid: Ulid, progress: &JobProgress) -> Result<(), StorageError> {
+        StorageProvider::set_progress(&mut **self, uid, progress).await
+    }
+
+    async fn save_checkpoint(&mut self, uid: Ulid, checkpoint: &Value) -> Result<(), StorageError> {
+        StorageProvider::save_checkpoint(&mut **self, uid, checkpoint).await
+    }
+}
+
+/// Handle passed to `Job::run` for reporting back to storage while the job runs
+pub struct JobContext<'a> {
+    metadata: &'a JobMetadata,
+    progress_interval: Duration,
+    state: Mutex<ContextState<'a>>,
+}
+
+struct ContextState<'a> {
+    storage: &'a mut dyn JobStorage,
+    pending_progress: Option<JobProgress>,
+    progress_written: Option<Instant>,
+}
+
+impl<'a> JobContext<'a> {
+    pub(crate) fn new(
+        metadata: &'a JobMetadata, storage: &'a mut dyn JobStorage, progress_interval: Duration,
+    ) -> Self {
+        Self {
+            metadata,
+            progress_interval,
+            state: Mutex::new(ContextState {
+                storage,
+                pending_progress: None,
+                progress_written: None,
+            }),
+        }
+    }
+
+    /// Metadata of the job as it was when this attempt started
+    pub fn metadata(&self) -> &JobMetadata {
+        self.metadata
+    }
+
+    /// Report how far along the job is, from 0 to 100 percent. Progress is written to storage at
+    /// most once per the executor's progress interval, the latest progress is always written when
+    /// the attempt ends.
+    pub async fn set_progress(&self, percent: f32, message: Option<&str>) -> Result<(), AJobQueueError> {
+        let mut state = self.state.lock().await;
+        state.pending_progress = Some(JobProgress {
+            percent: percent.clamp(0.0, 100.0),
+            message: message.map(str::to_string),
+        });
+
+        if state.progress_written.is_none_or(|written| written.elapsed() >= self.progress_interval) {
+            self.write_progress(&mut state).await?;
+        }
+        Ok(())
+    }
+
+    /// State saved by an earlier attempt of this job, to resume work from where it stopped. Jobs
+    /// requeued from the dead-letter area start without one.
+    pub fn checkpoint<T: DeserializeOwned>(&self) -> Result<Option<T>, AJobQueueError> {
+        Ok(self.metadata.checkpoint.clone()
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(StorageError::from)?)
+    }
+
+    /// Save state for later attempts to resume from if this one doesn't finish, written to
+    /// storage right away
+    pub async fn save_checkpoint<T: Serialize>(&self, checkpoint: &T) -> Result<(), AJobQueueError> {
+        let checkpoint = serde_json::to_value(checkpoint).map_err(StorageError::from)?;
+        self.state.lock().await.storage.save_checkpoint(self.metadata.uid, &checkpoint).await?;
+        Ok(())
+    }
+
+    /// Write progress held back by throttling
+    pub(crate) async fn flush(&self) -> Result<(), AJobQueueError> {
+        self.write_progress(&mut *self.state.lock().await).await
+    }
+
+    async fn write_progress(&self, state: &mut ContextState<'a>) -> Result<(), AJobQueueError> {
+        if let Some(progress) = state.pending_progress.take() {
+            state.storage.set_progress(self.metadata.uid, &progress).await?;
+            state.progress_written = Some(Instant::now());
+        }
+        Ok(())
+    }
+}
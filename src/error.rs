@@ -1,6 +1,8 @@
+use std::backtrace::{Backtrace, BacktraceStatus};
 use std::error::Error as StdError;
 
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 use thiserror::Error;
 use tokio::task::JoinError;
 
@@ -13,7 +15,13 @@ pub enum AJobQueueError {
     Storage(#[from] StorageError),
 
     #[error("Job run error")]
-    JobRun(#[from] JobRunError),
+    JobRun(#[source] Box<JobRunError>),
+}
+
+impl From<JobRunError> for AJobQueueError {
+    fn from(err: JobRunError) -> Self {
+        AJobQueueError::JobRun(Box::new(err))
+    }
 }
 
 #[derive(Error, Debug)]
@@ -23,24 +31,166 @@ pub enum ExecutionError {
 
     #[error("Failed to join executor tasks to main task: {0:?}")]
     JoinError(#[from] JoinError),
+
+    #[error("Executor stopped after a storage error: {0}")]
+    Storage(#[from] StorageError),
 }
 
 #[derive(Error, Debug)]
 pub enum StorageError {
+    #[cfg(feature = "postgres")]
     #[error(transparent)]
     Database(#[from] sqlx::Error),
 
     #[error("Failed to create task")]
     Serialization(#[source] Box<dyn StdError + Send + Sync>),
 
+    /// Storage can't be reached right now, retrying later may succeed
+    #[error("Storage unavailable: {0}")]
+    Unavailable(String),
+
     #[error("Unspecified error: {0}")]
     Unspecified(String),
 }
 
-#[derive(Error, Clone, Debug, Serialize, Deserialize)]
-pub enum JobRunError {
-    #[error("Task failure")]
-    TaskFailure {
-        msg: String,
-    },
+impl StorageError {
+    /// Whether the operation may succeed when retried, e.g. after a dropped connection or a
+    /// serialization failure. Other errors won't go away by themselves.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            #[cfg(feature = "postgres")]
+            StorageError::Database(err) => match err {
+                sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+                // Connection exceptions, serialization failures, deadlocks, too many connections
+                // and server shutdowns
+                sqlx::Error::Database(err) => err.code().is_some_and(|code| {
+                    code.starts_with("08")
+                        || code.starts_with("57P")
+                        || ["40001", "40P01", "53300"].contains(&code.as_ref())
+                }),
+                _ => false,
+            },
+            StorageError::Unavailable(_) => true,
+            StorageError::Serialization(_) | StorageError::Unspecified(_) => false,
+        }
+    }
+}
+
+/// Why a job attempt failed. Stored with the job, every attempt's error is kept in
+/// `JobMetadata::errors`.
+#[derive(Error, Clone, Debug, PartialEq, Serialize)]
+#[error("{message}")]
+pub struct JobRunError {
+    /// Machine readable kind of failure, e.g. `"timeout"`
+    pub code: Option<String>,
+    pub message: String,
+    /// Messages of the errors that led to this one, outermost first
+    pub causes: Vec<String>,
+    pub backtrace: Option<String>,
+    /// Any extra data worth keeping about the failure
+    pub details: Option<Value>,
+    /// Whether the job may be attempted again, jobs failing with a permanent error are
+    /// dead-lettered straight away
+    pub retryable: bool,
+}
+
+impl JobRunError {
+    /// Code of errors recorded for poisoned jobs, whose stored data couldn't be deserialized
+    pub const DESERIALIZATION_CODE: &'static str = "deserialization";
+
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            code: None,
+            message: message.into(),
+            causes: Vec::new(),
+            backtrace: None,
+            details: None,
+            retryable: true,
+        }
+    }
+
+    /// Build an error from `err`, keeping the messages of its sources as causes
+    pub fn from_error(err: &(dyn StdError + 'static)) -> Self {
+        let mut causes = Vec::new();
+        let mut source = err.source();
+        while let Some(err) = source {
+            causes.push(err.to_string());
+            source = err.source();
+        }
+
+        Self { causes, ..Self::new(err.to_string()) }
+    }
+
+    pub fn with_code(mut self, code: impl Into<String>) -> Self {
+        self.code = Some(code.into());
+        self
+    }
+
+    pub fn with_cause(mut self, cause: impl Into<String>) -> Self {
+        self.causes.push(cause.into());
+        self
+    }
+
+    /// Attach `details`, they're serialized to JSON to be stored with the job
+    pub fn with_details<T: Serialize>(mut self, details: &T) -> Result<Self, serde_json::Error> {
+        self.details = Some(serde_json::to_value(details)?);
+        Ok(self)
+    }
+
+    /// Capture a backtrace of the current thread, only when backtraces are enabled through
+    /// `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`
+    pub fn with_backtrace(mut self) -> Self {
+        let backtrace = Backtrace::capture();
+        if backtrace.status() == BacktraceStatus::Captured {
+            self.backtrace = Some(backtrace.to_string());
+        }
+        self
+    }
+
+    /// Don't retry the job, regardless of how many attempts it has left
+    pub fn permanent(mut self) -> Self {
+        self.retryable = false;
+        self
+    }
+
+    pub(crate) fn deserialization(message: impl Into<String>) -> Self {
+        JobRunError::new(message).with_code(Self::DESERIALIZATION_CODE).permanent()
+    }
+}
+
+impl<'de> Deserialize<'de> for JobRunError {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        enum Legacy {
+            TaskFailure { msg: String },
+        }
+
+        fn retryable() -> bool {
+            true
+        }
+
+        // Errors stored before they were structured are still read back
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Current {
+                code: Option<String>,
+                message: String,
+                #[serde(default)]
+                causes: Vec<String>,
+                backtrace: Option<String>,
+                details: Option<Value>,
+                #[serde(default = "retryable")]
+                retryable: bool,
+            },
+            Legacy(Legacy),
+        }
+
+        Ok(match Stored::deserialize(deserializer)? {
+            Stored::Current { code, message, causes, backtrace, details, retryable } => {
+                JobRunError { code, message, causes, backtrace, details, retryable }
+            }
+            Stored::Legacy(Legacy::TaskFailure { msg }) => JobRunError::new(msg),
+        })
+    }
 }
@@ -0,0 +1,65 @@
+use tokio::sync::broadcast;
+
+use crate::storage::JobMetadata;
+
+/// Change in a job's lifecycle, see `Queue::subscribe` and `Executor::subscribe`
+#[derive(Clone, Debug)]
+pub enum JobEvent {
+    Pushed(JobMetadata),
+    /// An executor started running an attempt of the job
+    Started(JobMetadata),
+    Completed(JobMetadata),
+    /// An attempt failed and the job was queued to run again
+    Retried(JobMetadata),
+    /// The last attempt failed and the job was moved to the dead-letter area
+    Failed(JobMetadata),
+    /// A pulled job was handed back to storage unfinished when its executor stopped, either before
+    /// it started or part way through. The metadata is as it was when the job was pulled.
+    Cancelled(JobMetadata),
+}
+
+impl JobEvent {
+    pub fn metadata(&self) -> &JobMetadata {
+        match self {
+            JobEvent::Pushed(metadata)
+            | JobEvent::Started(metadata)
+            | JobEvent::Completed(metadata)
+            | JobEvent::Retried(metadata)
+            | JobEvent::Failed(metadata)
+            | JobEvent::Cancelled(metadata) => metadata,
+        }
+    }
+}
+
+/// Subscription to job events
+pub struct JobEvents {
+    receiver: broadcast::Receiver<JobEvent>,
+    missed: u64,
+}
+
+impl JobEvents {
+    pub(crate) fn new(receiver: broadcast::Receiver<JobEvent>) -> Self {
+        Self { receiver, missed: 0 }
+    }
+
+    /// Wait for the next event, returns `None` once the queue or executor is gone. Events are
+    /// buffered per subscriber, a subscriber that falls too far behind skips the oldest ones.
+    pub async fn next(&mut self) -> Option<JobEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => self.missed += missed,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// How many events were skipped because this subscriber fell behind
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+pub(crate) fn channel() -> broadcast::Sender<JobEvent> {
+    broadcast::channel(1024).0
+}
@@ -1,30 +1,195 @@
-use crate::error::ExecutionError;
-use crate::error::StorageError;
-use crate::JobTypeMarker;
+use std::collections::{HashMap, VecDeque};
+use std::future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::context::JobContext;
+use crate::error::{ExecutionError, StorageError};
+use crate::events::{self, JobEvent, JobEvents};
+#[cfg(feature = "metrics")]
+use crate::instrumentation;
+use crate::middleware::Middleware;
+use crate::storage::{JobAttempt, JobInfo, JobMetadata, JobState, RateLimit, RetentionPolicy};
+#[cfg(feature = "tracing")]
+use crate::telemetry;
+use crate::{JobRunError, JobType, JobTypeMarker};
 
 use super::Job;
 use super::StorageProvider;
 
+use chrono::Utc;
 use tokio::select;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio::task::JoinHandle;
-use tokio::time::{self, Duration};
+use tokio::time::{self, Duration, Instant};
 use tokio::{sync::broadcast, task};
+#[cfg(feature = "tracing")]
+use tracing::Instrument;
+use ulid::Ulid;
+
+/// How often executors sharing a concurrency budget look for jobs, they don't hold on to permits
+/// while waiting for one
+const SHARED_BUDGET_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Debug)]
 enum BroadcastMessage {
     Shutdown,
 }
 
+/// How an executor rides out transient storage errors. Failed calls are retried with exponential
+/// backoff, after `failure_threshold` failures in a row the circuit opens and storage is left
+/// alone for `open_duration` before trying again. Other errors stop the executor.
+#[derive(Clone, Debug)]
+pub struct StorageRetryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub failure_threshold: u32,
+    pub open_duration: Duration,
+}
+
+impl Default for StorageRetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Tracks consecutive storage failures, see `StorageRetryPolicy`
+struct CircuitBreaker {
+    policy: StorageRetryPolicy,
+    failures: u32,
+    open: Arc<AtomicBool>,
+}
+
+impl CircuitBreaker {
+    fn succeeded(&mut self) {
+        self.failures = 0;
+        self.open.store(false, Ordering::Relaxed);
+    }
+
+    /// Wait before the failed call is retried, or give the error back when retrying won't help
+    async fn failed(&mut self, err: StorageError, action: &str) -> Result<(), StorageError> {
+        if !err.is_transient() {
+            log::error!("Failed to {}: {}", action, err);
+            return Err(err);
+        }
+
+        self.failures += 1;
+        let wait = if self.failures >= self.policy.failure_threshold {
+            self.open.store(true, Ordering::Relaxed);
+            self.policy.open_duration
+        } else {
+            self.policy.initial_backoff
+                .saturating_mul(2u32.saturating_pow(self.failures - 1))
+                .min(self.policy.max_backoff)
+        };
+
+        log::warn!("Failed to {}, retrying in {:?}: {}", action, wait, err);
+        time::sleep(wait).await;
+        Ok(())
+    }
+}
+
 pub struct Executor<J: JobTypeMarker + ?Sized> {
     job_type_data: J::JobTypeData,
     storage_provider: Box<dyn StorageProvider<J>>,
+    prefetch: usize,
+    pruner: Option<(Box<dyn StorageProvider<J>>, RetentionPolicy)>,
+    middleware: Vec<Box<dyn Middleware<J>>>,
+    events: broadcast::Sender<JobEvent>,
+    permits: Option<Arc<Semaphore>>,
+    rate_limit: Option<RateLimit>,
+    progress_interval: Duration,
+    circuit_breaker: CircuitBreaker,
+    worker_id: String,
 }
 
 impl<J: JobTypeMarker + ?Sized + 'static> Executor<J> {
     pub fn new<S: StorageProvider<J> + 'static>(
         storage_provider: S, job_type_data: J::JobTypeData,
     ) -> Self {
-        Self { job_type_data, storage_provider: Box::new(storage_provider) }
+        Self {
+            job_type_data,
+            storage_provider: Box::new(storage_provider),
+            prefetch: 1,
+            pruner: None,
+            middleware: Vec::new(),
+            events: events::channel(),
+            permits: None,
+            rate_limit: None,
+            progress_interval: Duration::from_secs(1),
+            circuit_breaker: CircuitBreaker {
+                policy: StorageRetryPolicy::default(),
+                failures: 0,
+                open: Arc::new(AtomicBool::new(false)),
+            },
+            worker_id: Ulid::new().to_string(),
+        }
+    }
+
+    /// Pull up to `prefetch` jobs per round trip to storage, buffering them locally. Buffered jobs
+    /// that haven't started yet are released back to storage when the executor is stopped.
+    pub fn with_prefetch(mut self, prefetch: usize) -> Self {
+        self.prefetch = prefetch.max(1);
+        self
+    }
+
+    /// Prune finished jobs from `storage_provider` according to `retention` in the background
+    /// while the executor is running.
+    pub fn with_retention<S: StorageProvider<J> + 'static>(
+        mut self, storage_provider: S, retention: RetentionPolicy,
+    ) -> Self {
+        self.pruner = Some((Box::new(storage_provider), retention));
+        self
+    }
+
+    /// Add middleware to call around every job run, in the order it was added
+    pub fn with_middleware<M: Middleware<J> + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Limit how often jobs of this type start, across every executor sharing the same storage.
+    /// Jobs over the limit wait in the prefetch buffer until the next window while other buffered
+    /// jobs run, so a prefetch above one keeps jobs of other keys going. Jobs with a
+    /// `Job::rate_limit_key` are limited per key instead.
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Write progress reported by jobs to storage at most once per `progress_interval`, defaults
+    /// to a second
+    pub fn with_progress_interval(mut self, progress_interval: Duration) -> Self {
+        self.progress_interval = progress_interval;
+        self
+    }
+
+    /// Retry transient storage errors according to `retry_policy` instead of the default policy
+    pub fn with_storage_retry_policy(mut self, retry_policy: StorageRetryPolicy) -> Self {
+        self.circuit_breaker.policy = retry_policy;
+        self
+    }
+
+    /// Identify this executor in the attempt history of the jobs it runs, defaults to a random id
+    pub fn with_worker_id(mut self, worker_id: impl Into<String>) -> Self {
+        self.worker_id = worker_id.into();
+        self
+    }
+
+    /// Share a budget of concurrently running jobs with other executors
+    pub(crate) fn with_permits(mut self, permits: Option<Arc<Semaphore>>) -> Self {
+        self.permits = permits;
+        self
+    }
+
+    /// Receive an event whenever this executor starts, finishes or hands back a job
+    pub fn subscribe(&self) -> JobEvents {
+        JobEvents::new(self.events.subscribe())
     }
 
     pub fn start(self) -> RunningExecutor {
@@ -32,41 +197,305 @@ impl<J: JobTypeMarker + ?Sized + 'static> Executor<J> {
         let (notifier_sender, notifier_receiver) = broadcast::channel(10);
 
         let run_notifier_sender = notifier_sender.clone();
-
-        let join = task::spawn(async move {
-            select! {
-                _ = self.run(run_notifier_sender) => {}
-                _ = manage_signals(receiver) => {}
-            }
-        });
+        let events = self.events.clone();
+        let circuit_open = self.circuit_breaker.open.clone();
+        let join = task::spawn(self.run(receiver, run_notifier_sender));
 
         RunningExecutor {
             task_handle: join,
             broadcast_channel: sender,
             notifier: (notifier_sender, notifier_receiver),
             waited_for: 0,
+            events,
+            job_type: J::job_type(),
+            circuit_open,
         }
     }
 
-    async fn run(mut self, notifier: broadcast::Sender<u32>) {
+    async fn run(
+        mut self,
+        receiver: broadcast::Receiver<BroadcastMessage>,
+        notifier: broadcast::Sender<u32>,
+    ) -> Result<(), StorageError> {
+        let mut buffer = VecDeque::new();
+        let mut running = None;
+        let pruner = self.pruner.take();
+
+        let result = select! {
+            result = self.process(&mut buffer, &mut running, notifier) => result,
+            _ = prune_storage(pruner) => Ok(()),
+            _ = manage_signals(receiver) => Ok(()),
+        };
+
+        // Stopping drops the job that was running, it's handed back with the buffered jobs to be
+        // run again from its last checkpoint
+        let unfinished: Vec<_> = running.into_iter()
+            .chain(buffer.into_iter().map(|job_info| job_info.metadata))
+            .collect();
+        if !unfinished.is_empty() {
+            let uids: Vec<_> = unfinished.iter().map(|metadata| metadata.uid).collect();
+            match self.storage_provider.release(&uids).await {
+                Ok(()) => for metadata in unfinished {
+                    let _ = self.events.send(JobEvent::Cancelled(metadata));
+                },
+                Err(err) => log::error!("Failed to release {} unfinished jobs: {}", uids.len(), err),
+            }
+        }
+
+        result
+    }
+
+    /// Run jobs until stopped, keeping the job being run in `running` until its result is stored
+    async fn process(
+        &mut self,
+        buffer: &mut VecDeque<JobInfo<J>>,
+        running: &mut Option<JobMetadata>,
+        notifier: broadcast::Sender<u32>,
+    ) -> Result<(), StorageError> {
         let mut i = 0;
+        let mut rate_limited = HashMap::new();
+        // Permits from the shared budget for the buffered jobs, one each
+        let mut buffer_permits = Vec::new();
         loop {
-            let result = self.storage_provider.pull().await;
-            if let Err(StorageError::Database(sqlx::Error::RowNotFound)) = result {
-                time::sleep(Duration::from_secs(1)).await;
+            if buffer.is_empty() {
+                self.fill_buffer(buffer, &mut buffer_permits, self.prefetch, Duration::from_secs(60)).await?;
                 continue;
             }
-            let job_info = result.expect("Failed to fetch job");
-            Job::run(&*job_info.job, &self.job_type_data).await;
-            self.storage_provider
-                .set_job_result(job_info.metadata.uid, Ok(()))
-                .await
-                .expect("Failed to set job result");
+
+            let index = match self.next_job(buffer, &mut rate_limited).await? {
+                Some(index) => index,
+                None => {
+                    // Every buffered job is over its rate limit, look for other jobs while there's
+                    // room in the buffer until the first limit frees up
+                    let wait = rate_limited.values().min()
+                        .expect("No rate limited jobs")
+                        .saturating_duration_since(Instant::now());
+                    match self.prefetch.saturating_sub(buffer.len()) {
+                        0 => time::sleep(wait).await,
+                        room => self.fill_buffer(buffer, &mut buffer_permits, room, wait).await?,
+                    }
+                    continue;
+                }
+            };
+
+            let permit = buffer_permits.pop();
+            let job_info = buffer.remove(index).expect("Job buffer is empty");
+            *running = Some(job_info.metadata.clone());
+            let _ = self.events.send(JobEvent::Started(job_info.metadata.clone()));
+            let started = Utc::now();
+            #[cfg(feature = "metrics")]
+            let run_started = Instant::now();
+            let job_run = self.run_job(&job_info);
+            #[cfg(feature = "tracing")]
+            let job_run = job_run.instrument(telemetry::job_span(&job_info.metadata));
+            let job_result = job_run.await;
+            let finished = Utc::now();
+            #[cfg(feature = "metrics")]
+            instrumentation::job_finished(&J::job_type(), job_result.is_ok(), run_started.elapsed());
+            // The job already ran, so its result is retried rather than the job being handed back
+            let metadata = loop {
+                match self.storage_provider.set_job_result(job_info.metadata.uid, job_result.clone()).await {
+                    Ok(metadata) => break metadata,
+                    Err(err) => self.circuit_breaker.failed(err, "set job result").await?,
+                }
+            };
+            *running = None;
+            self.circuit_breaker.succeeded();
+
+            let attempt = JobAttempt {
+                job_uid: job_info.metadata.uid,
+                attempt: job_info.metadata.attempts,
+                started,
+                finished,
+                worker_id: self.worker_id.clone(),
+                error: job_result.err(),
+            };
+            if let Err(err) = self.storage_provider.record_attempt(&attempt).await {
+                log::error!("Failed to record attempt of job {}: {}", attempt.job_uid, err);
+            }
+
+            let event = match metadata.state {
+                JobState::Completed => JobEvent::Completed(metadata),
+                JobState::Failed => JobEvent::Failed(metadata),
+                _ => JobEvent::Retried(metadata),
+            };
+            let _ = self.events.send(event);
+            drop(permit);
 
             i += 1;
             let _ = notifier.send(i);
         }
     }
+
+    /// Add up to `count` available jobs to `buffer`, or wait up to `wait` for the next one when
+    /// there are none. With a shared budget, jobs are only pulled with a permit to run them, which
+    /// is added to `buffer_permits`.
+    async fn fill_buffer(
+        &mut self,
+        buffer: &mut VecDeque<JobInfo<J>>,
+        buffer_permits: &mut Vec<OwnedSemaphorePermit>,
+        count: usize,
+        wait: Duration,
+    ) -> Result<(), StorageError> {
+        let acquired = match &self.permits {
+            Some(budget) => {
+                let acquired = acquire_permits(budget, count, buffer.is_empty()).await;
+                if acquired.is_empty() {
+                    time::sleep(wait).await;
+                    return Ok(());
+                }
+                Some(acquired)
+            }
+            None => None,
+        };
+
+        let pulled = match &acquired {
+            Some(acquired) => self.pull_available(acquired.len()).await,
+            None => self.pull_jobs(count, wait).await,
+        };
+        let jobs = match pulled {
+            Ok(jobs) => jobs,
+            Err(err) => return self.circuit_breaker.failed(err, "fetch jobs").await,
+        };
+        self.circuit_breaker.succeeded();
+        #[cfg(feature = "metrics")]
+        instrumentation::jobs_pulled(&J::job_type(), jobs.len());
+
+        if let Some(acquired) = acquired {
+            if jobs.is_empty() {
+                drop(acquired);
+                time::sleep(wait.min(SHARED_BUDGET_POLL_INTERVAL)).await;
+                return Ok(());
+            }
+            buffer_permits.extend(acquired.into_iter().take(jobs.len()));
+        }
+        buffer.extend(jobs);
+        Ok(())
+    }
+
+    /// Take up to `count` available jobs, or wait up to `wait` for the next one when there are
+    /// none. Stopping the executor interrupts the wait, so it can be long.
+    async fn pull_jobs(&mut self, count: usize, wait: Duration) -> Result<Vec<JobInfo<J>>, StorageError> {
+        let jobs = self.pull_available(count).await?;
+        if !jobs.is_empty() {
+            return Ok(jobs);
+        }
+
+        Ok(self.storage_provider.pull_with_timeout(wait).await?.into_iter().collect())
+    }
+
+    /// Take up to `count` available jobs without waiting for more
+    async fn pull_available(&mut self, count: usize) -> Result<Vec<JobInfo<J>>, StorageError> {
+        #[cfg(feature = "metrics")]
+        let pull_started = Instant::now();
+        let jobs = self.storage_provider.pull_many(count).await?;
+        #[cfg(feature = "metrics")]
+        if !jobs.is_empty() {
+            instrumentation::pull_finished(&J::job_type(), pull_started.elapsed());
+        }
+        Ok(jobs)
+    }
+
+    /// Index of the first buffered job allowed to start by the rate limit, taking a slot for it.
+    /// Keys over their limit are kept in `rate_limited` until their window ends, so their jobs are
+    /// skipped without asking storage again.
+    async fn next_job(
+        &mut self, buffer: &VecDeque<JobInfo<J>>, rate_limited: &mut HashMap<String, Instant>,
+    ) -> Result<Option<usize>, StorageError> {
+        let rate_limit = match &self.rate_limit {
+            Some(rate_limit) => rate_limit,
+            None => return Ok(Some(0)),
+        };
+
+        let now = Instant::now();
+        rate_limited.retain(|_, until| *until > now);
+        for (index, job_info) in buffer.iter().enumerate() {
+            let key = match job_info.job.rate_limit_key() {
+                Some(key) => format!("{}:{}", J::job_type(), key),
+                None => J::job_type(),
+            };
+            if rate_limited.contains_key(&key) {
+                continue;
+            }
+
+            loop {
+                match self.storage_provider.acquire_rate_limit(&key, rate_limit).await {
+                    Ok(Some(wait)) => {
+                        rate_limited.insert(key, now + wait);
+                        break;
+                    }
+                    Ok(None) => return Ok(Some(index)),
+                    Err(err) => self.circuit_breaker.failed(err, "acquire rate limit").await?,
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn run_job(&mut self, job_info: &JobInfo<J>) -> Result<(), JobRunError> {
+        let mut job_result = Ok(());
+        // Only middleware whose before_run succeeded gets the hooks after the attempt
+        let mut entered = 0;
+        for middleware in &self.middleware {
+            job_result = middleware.before_run(job_info).await;
+            if job_result.is_err() {
+                break;
+            }
+            entered += 1;
+        }
+
+        if job_result.is_ok() {
+            let context = JobContext::new(
+                &job_info.metadata, &mut self.storage_provider, self.progress_interval,
+            );
+            job_result = Job::run(&*job_info.job, &self.job_type_data, &context).await;
+            if let Err(err) = context.flush().await {
+                log::error!("Failed to save progress of job {}: {}", job_info.metadata.uid, err);
+            }
+        }
+
+        for middleware in self.middleware[..entered].iter().rev() {
+            if let Err(err) = &job_result {
+                middleware.on_failure(job_info, err).await;
+            }
+            middleware.after_run(job_info, &job_result).await;
+        }
+
+        job_result
+    }
+}
+
+/// Take up to `count` permits from `budget` without waiting, or wait for the first one if `wait`
+async fn acquire_permits(budget: &Arc<Semaphore>, count: usize, wait: bool) -> Vec<OwnedSemaphorePermit> {
+    let mut acquired = Vec::with_capacity(count);
+    if wait {
+        acquired.push(budget.clone().acquire_owned().await.expect("Concurrency permits closed"));
+    }
+    while acquired.len() < count {
+        match budget.clone().try_acquire_owned() {
+            Ok(permit) => acquired.push(permit),
+            Err(_) => break,
+        }
+    }
+    acquired
+}
+
+async fn prune_storage<J: JobTypeMarker + ?Sized>(
+    pruner: Option<(Box<dyn StorageProvider<J>>, RetentionPolicy)>,
+) {
+    let (mut storage_provider, retention) = match pruner {
+        Some(pruner) => pruner,
+        None => return future::pending().await,
+    };
+
+    loop {
+        time::sleep(retention.interval).await;
+        match storage_provider.prune(&retention).await {
+            Ok(pruned) => log::debug!("Pruned {} finished jobs", pruned),
+            Err(err) => log::error!("Failed to prune finished jobs: {}", err),
+        }
+    }
 }
 
 async fn manage_signals(mut receiver: broadcast::Receiver<BroadcastMessage>) {
@@ -80,18 +509,52 @@ async fn manage_signals(mut receiver: broadcast::Receiver<BroadcastMessage>) {
 }
 
 pub struct RunningExecutor {
-    task_handle: JoinHandle<()>,
+    task_handle: JoinHandle<Result<(), StorageError>>,
     broadcast_channel: broadcast::Sender<BroadcastMessage>,
     notifier: (broadcast::Sender<u32>, broadcast::Receiver<u32>),
     waited_for: u32,
+    events: broadcast::Sender<JobEvent>,
+    job_type: String,
+    circuit_open: Arc<AtomicBool>,
 }
 
 impl RunningExecutor {
+    pub fn job_type(&self) -> &str {
+        &self.job_type
+    }
+
+    /// Whether the executor is still processing jobs, false once it's stopped or has crashed
+    pub fn is_running(&self) -> bool {
+        !self.task_handle.is_finished()
+    }
+
+    /// Whether the executor is holding off storage after repeated transient errors
+    pub fn is_circuit_open(&self) -> bool {
+        self.circuit_open.load(Ordering::Relaxed)
+    }
+
+    /// Receive an event whenever the executor starts, finishes or hands back a job
+    pub fn subscribe(&self) -> JobEvents {
+        JobEvents::new(self.events.subscribe())
+    }
+
+    /// Stop the executor, returning the storage error it stopped on if it already stopped by itself.
+    /// The job it's running is interrupted and released back to storage along with its buffered
+    /// jobs.
     pub async fn stop(self) -> Result<(), ExecutionError> {
-        self.broadcast_channel
-            .send(BroadcastMessage::Shutdown)
-            .map_err(|x| ExecutionError::SignalingError(Box::new(x)))?;
-        self.task_handle.await.map_err(ExecutionError::JoinError)?;
+        self.signal_stop();
+        self.join().await
+    }
+
+    /// Tell the executor to stop without waiting for it
+    pub(crate) fn signal_stop(&self) {
+        // Sending only fails once the executor has stopped and dropped its receiver
+        let _ = self.broadcast_channel.send(BroadcastMessage::Shutdown);
+    }
+
+    /// Wait for the executor to stop, returning the storage error it stopped on
+    pub(crate) async fn join(self) -> Result<(), ExecutionError> {
+        self.task_handle.await.map_err(ExecutionError::JoinError)??;
         Ok(())
     }
 
@@ -0,0 +1,60 @@
+//! Metrics emitted through the `metrics` facade. Install a recorder, e.g.
+//! `metrics-exporter-prometheus`, to expose them.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::storage::{JobState, JobTypeStats, QueueStats};
+
+pub(crate) fn jobs_pushed(job_type: &str, count: usize) {
+    metrics::counter!("ajobqueue_jobs_pushed_total", count as u64, "job_type" => job_type.to_string());
+}
+
+pub(crate) fn jobs_pulled(job_type: &str, count: usize) {
+    metrics::counter!("ajobqueue_jobs_pulled_total", count as u64, "job_type" => job_type.to_string());
+}
+
+/// Only round trips that came back with jobs are timed, waiting for jobs isn't storage latency
+pub(crate) fn pull_finished(job_type: &str, latency: Duration) {
+    metrics::histogram!("ajobqueue_pull_duration_seconds", latency, "job_type" => job_type.to_string());
+}
+
+pub(crate) fn job_finished(job_type: &str, succeeded: bool, duration: Duration) {
+    if succeeded {
+        metrics::increment_counter!("ajobqueue_jobs_completed_total", "job_type" => job_type.to_string());
+    } else {
+        metrics::increment_counter!("ajobqueue_jobs_failed_total", "job_type" => job_type.to_string());
+    }
+    metrics::histogram!("ajobqueue_job_duration_seconds", duration, "job_type" => job_type.to_string());
+}
+
+/// Queue depth can't be tracked from a single process, so the gauges are refreshed from stats.
+/// Gauges keep their last value, so states and job types that dropped out of the stats since
+/// `reported_job_types` were last reported are set back to zero.
+pub(crate) fn queue_depth(stats: &QueueStats, reported_job_types: &mut HashSet<String>) {
+    let no_jobs = JobTypeStats::default();
+    let job_types: HashSet<String> = reported_job_types.drain()
+        .chain(stats.job_types.keys().cloned())
+        .collect();
+
+    for job_type in job_types {
+        let job_stats = stats.job_types.get(&job_type).unwrap_or(&no_jobs);
+        for state in JobState::ALL {
+            let count = job_stats.counts.get(&state).copied().unwrap_or_default();
+            metrics::gauge!(
+                "ajobqueue_queue_depth", count as f64,
+                "job_type" => job_type.clone(), "state" => state.as_str(),
+            );
+        }
+
+        let oldest_pending_age = job_stats.oldest_pending_age.unwrap_or_default();
+        metrics::gauge!(
+            "ajobqueue_oldest_pending_seconds", oldest_pending_age,
+            "job_type" => job_type.clone(),
+        );
+
+        if stats.job_types.contains_key(&job_type) {
+            reported_job_types.insert(job_type);
+        }
+    }
+}
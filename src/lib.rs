@@ -1,34 +1,82 @@
 extern crate self as ajobqueue;
 
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
 use async_trait::async_trait;
 use serde::Serialize;
-use storage::JobMetadata;
+use tokio::sync::broadcast;
+use storage::{DeadLetter, JobAttempt, JobFilter, JobMetadata, JobPage, QueueStats};
 use ulid::Ulid;
 
+mod context;
 mod error;
+mod events;
 mod executor;
+#[cfg(feature = "metrics")]
+mod instrumentation;
+pub mod middleware;
+mod pool;
 pub mod storage;
+#[cfg(feature = "tracing")]
+mod telemetry;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 pub use ajobqueue_macro::*;
-pub use error::AJobQueueError;
-pub use executor::{Executor, RunningExecutor};
+pub use context::JobContext;
+pub use error::{AJobQueueError, ExecutionError, JobRunError, StorageError};
+pub use events::{JobEvent, JobEvents};
+pub use executor::{Executor, RunningExecutor, StorageRetryPolicy};
+pub use middleware::Middleware;
+pub use pool::{ExecutorHealth, ExecutorPool, PoolHealth, RunningExecutorPool};
 pub use storage::StorageProvider;
 
 #[doc(hidden)]
 pub use typetag;
+#[doc(hidden)]
+pub use linkme;
 
 #[async_trait]
 pub trait Job: Sync + Send + Debug {
     type JobTypeData: JobType;
-    async fn run(&self, job_data: &Self::JobTypeData);
+    async fn run(&self, job_data: &Self::JobTypeData, context: &JobContext) -> Result<(), JobRunError>;
+
+    /// How many times the job is run before it's moved to the dead-letter area
+    fn max_attempts(&self) -> u32 {
+        1
+    }
+
+    /// Rate limit this job separately from other jobs of its type, e.g. per customer. Only used
+    /// when the executor has a rate limit set with `Executor::with_rate_limit`.
+    fn rate_limit_key(&self) -> Option<String> {
+        None
+    }
+
+    /// Jobs sharing a concurrency key, e.g. a tenant id, run at most `concurrency_limit` at a time
+    /// across every executor using the same storage. Other jobs are pulled ahead of them meanwhile.
+    fn concurrency_key(&self) -> Option<String> {
+        None
+    }
+
+    /// How many jobs with this job's `concurrency_key` may run at once
+    fn concurrency_limit(&self) -> u32 {
+        1
+    }
 }
 
-pub trait JobTypeMarker: Job + Serialize {}
+pub trait JobTypeMarker: Job + Serialize {
+    /// Version of the job's payload, set with `#[job(Type, version = N)]`. Jobs stored with an
+    /// older version are upgraded by the migrations registered with `#[job_migration]`.
+    fn job_version(&self) -> u32;
+}
 
 pub trait JobType: Send + Sync {
     fn job_type() -> String;
+
+    /// Previous names of the job type, jobs stored under them are still pulled
+    fn job_type_aliases() -> Vec<String> {
+        Vec::new()
+    }
 }
 
 impl<J: ?Sized, T> JobType for J
@@ -39,33 +87,161 @@ where
     fn job_type() -> String {
         T::job_type()
     }
+
+    fn job_type_aliases() -> Vec<String> {
+        T::job_type_aliases()
+    }
+}
+
+/// Previous name of a job, registered by `#[job(Type, alias = "...")]`
+#[doc(hidden)]
+pub struct JobAlias {
+    pub job_type: fn() -> String,
+    pub alias: &'static str,
+    pub name: &'static str,
+}
+
+#[doc(hidden)]
+#[linkme::distributed_slice]
+pub static JOB_ALIASES: [JobAlias] = [..];
+
+/// Implemented by `#[job]` so migrations can refer to jobs by their struct
+#[doc(hidden)]
+pub trait NamedJob {
+    type JobType: JobType;
+    const NAME: &'static str;
+}
+
+/// Upgrade of a job's payload from version `from` to `from + 1`, registered by `#[job_migration]`
+#[doc(hidden)]
+pub struct JobMigration {
+    pub job_type: fn() -> String,
+    pub name: &'static str,
+    pub from: u32,
+    pub migrate: fn(serde_json::Value) -> Result<serde_json::Value, serde_json::Error>,
 }
 
+#[doc(hidden)]
+#[linkme::distributed_slice]
+pub static JOB_MIGRATIONS: [JobMigration] = [..];
+
 pub struct Queue<J: JobTypeMarker + ?Sized> {
     storage_provider: Box<dyn StorageProvider<J>>,
+    middleware: Vec<Box<dyn Middleware<J>>>,
+    events: broadcast::Sender<JobEvent>,
+    /// Job types with queue depth gauges, to zero them once their jobs are gone
+    #[cfg(feature = "metrics")]
+    reported_job_types: std::sync::Mutex<std::collections::HashSet<String>>,
 }
 
 impl<J: JobTypeMarker + ?Sized> Queue<J> {
     pub fn new<S: StorageProvider<J> + 'static>(storage_provider: S) -> Self {
         Queue {
             storage_provider: Box::new(storage_provider),
+            middleware: Vec::new(),
+            events: events::channel(),
+            #[cfg(feature = "metrics")]
+            reported_job_types: Default::default(),
         }
     }
 
+    /// Receive an event for every job pushed through this queue, including requeued dead letters
+    pub fn subscribe(&self) -> JobEvents {
+        JobEvents::new(self.events.subscribe())
+    }
+
+    /// Add middleware to call whenever jobs are pushed
+    pub fn with_middleware<M: Middleware<J> + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
     pub async fn push_job(&mut self, job: &J) -> Result<JobMetadata, AJobQueueError> {
-        Ok(self.storage_provider.push(job).await?)
+        let metadata = self.storage_provider.push(job).await?;
+        #[cfg(feature = "metrics")]
+        instrumentation::jobs_pushed(&metadata.job_type, 1);
+        for middleware in &self.middleware {
+            middleware.on_push(job, &metadata).await;
+        }
+        let _ = self.events.send(JobEvent::Pushed(metadata.clone()));
+        Ok(metadata)
+    }
+
+    pub async fn push_jobs(&mut self, jobs: &[&J]) -> Result<Vec<JobMetadata>, AJobQueueError> {
+        let metadata = self.storage_provider.push_many(jobs).await?;
+        #[cfg(feature = "metrics")]
+        instrumentation::jobs_pushed(&J::job_type(), metadata.len());
+        for middleware in &self.middleware {
+            for (job, metadata) in jobs.iter().zip(&metadata) {
+                middleware.on_push(*job, metadata).await;
+            }
+        }
+        for metadata in &metadata {
+            let _ = self.events.send(JobEvent::Pushed(metadata.clone()));
+        }
+        Ok(metadata)
     }
 
     pub async fn get_job(&self, job_uid: Ulid) -> Result<JobMetadata, AJobQueueError> {
         Ok(self.storage_provider.get_job(job_uid).await?)
     }
+
+    pub async fn list_jobs(&self, filter: &JobFilter) -> Result<JobPage, AJobQueueError> {
+        Ok(self.storage_provider.list_jobs(filter).await?)
+    }
+
+    /// Every recorded attempt of a job, oldest first
+    pub async fn list_attempts(&self, job_uid: Ulid) -> Result<Vec<JobAttempt>, AJobQueueError> {
+        Ok(self.storage_provider.list_attempts(job_uid).await?)
+    }
+
+    /// With the `metrics` feature enabled this also refreshes the queue depth gauges, so call it
+    /// periodically (e.g. before each scrape) to keep them current.
+    pub async fn stats(&self, window: Duration) -> Result<QueueStats, AJobQueueError> {
+        let stats = self.storage_provider.stats(window).await?;
+        #[cfg(feature = "metrics")]
+        instrumentation::queue_depth(
+            &stats,
+            &mut self.reported_job_types.lock().unwrap_or_else(|x| x.into_inner()),
+        );
+        Ok(stats)
+    }
+
+    pub async fn list_dead_letters(&self) -> Result<Vec<DeadLetter>, AJobQueueError> {
+        Ok(self.storage_provider.list_dead_letters().await?)
+    }
+
+    pub async fn get_dead_letter(&self, job_uid: Ulid) -> Result<DeadLetter, AJobQueueError> {
+        Ok(self.storage_provider.get_dead_letter(job_uid).await?)
+    }
+
+    pub async fn requeue_dead_letter(
+        &mut self, job_uid: Ulid, job: Option<&J>,
+    ) -> Result<JobMetadata, AJobQueueError> {
+        let metadata = self.storage_provider.requeue_dead_letter(job_uid, job).await?;
+        let _ = self.events.send(JobEvent::Pushed(metadata.clone()));
+        Ok(metadata)
+    }
+
+    pub async fn purge_dead_letters(&mut self, job_uids: &[Ulid]) -> Result<u64, AJobQueueError> {
+        Ok(self.storage_provider.purge_dead_letters(job_uids).await?)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
+    use serde_json::Value;
     use tokio::{time::Duration, sync::Mutex};
-    use crate::{job, job_type, storage::InMemoryStorageProvider, Executor, Job, Queue};
+    use crate::{
+        job, job_migration, job_type, ExecutionError, Executor, ExecutorPool, Job, JobContext,
+        JobEvent, JobRunError, Middleware, Queue, StorageError, StorageProvider,
+        StorageRetryPolicy, testing::FlakyStorageProvider,
+        storage::{
+            InMemoryStorageProvider, JobFilter, JobInfo, JobMetadata, JobProgress, JobState,
+            RateLimit, Retention, RetentionPolicy,
+        },
+    };
     use async_trait::async_trait;
 
     // Job type 1
@@ -84,9 +260,10 @@ mod tests {
     impl Job for MockJob {
         type JobTypeData = MockJobType;
 
-        async fn run(&self, job_data: &Self::JobTypeData) {
+        async fn run(&self, job_data: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
             let msg = format!("MSG: {}, {}", job_data.data_msg_type, self.msg);
             job_data.shared_data.lock().await.push(msg);
+            Ok(())
         }
     }
 
@@ -99,9 +276,152 @@ mod tests {
     impl Job for MockJob2 {
         type JobTypeData = MockJobType;
 
-        async fn run(&self, job_data: &Self::JobTypeData) {
+        async fn run(&self, job_data: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
             let msg = format!("MSG2: {}, {}", job_data.data_msg_type, self.msg);
             job_data.shared_data.lock().await.push(msg);
+            Ok(())
+        }
+    }
+
+    #[job(MockJobType)]
+    struct FailingJob {
+        msg: String,
+    }
+
+    #[async_trait]
+    impl Job for FailingJob {
+        type JobTypeData = MockJobType;
+
+        async fn run(&self, job_data: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            let msg = format!("FAIL: {}, {}", job_data.data_msg_type, self.msg);
+            job_data.shared_data.lock().await.push(msg.clone());
+            Err(JobRunError::new(msg))
+        }
+
+        fn max_attempts(&self) -> u32 {
+            2
+        }
+    }
+
+    #[job(MockJobType)]
+    struct TenantJob {
+        tenant: String,
+    }
+
+    #[async_trait]
+    impl Job for TenantJob {
+        type JobTypeData = MockJobType;
+
+        async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            Ok(())
+        }
+
+        fn concurrency_key(&self) -> Option<String> {
+            Some(self.tenant.clone())
+        }
+    }
+
+    #[job(MockJobType)]
+    struct RateLimitedJob {
+        key: String,
+    }
+
+    #[async_trait]
+    impl Job for RateLimitedJob {
+        type JobTypeData = MockJobType;
+
+        async fn run(&self, job_data: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            job_data.shared_data.lock().await.push(self.key.clone());
+            Ok(())
+        }
+
+        fn rate_limit_key(&self) -> Option<String> {
+            Some(self.key.clone())
+        }
+    }
+
+    #[job(MockJobType, version = 2)]
+    struct MigratedJob {
+        messages: Vec<String>,
+    }
+
+    #[async_trait]
+    impl Job for MigratedJob {
+        type JobTypeData = MockJobType;
+
+        async fn run(&self, job_data: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            job_data.shared_data.lock().await.extend(self.messages.iter().cloned());
+            Ok(())
+        }
+    }
+
+    #[job_migration(MigratedJob, from = 1)]
+    fn wrap_msg(data: Value) -> Result<Value, serde_json::Error> {
+        Ok(serde_json::json!({ "messages": [data["msg"]] }))
+    }
+
+    #[job(MockJobType)]
+    struct ProgressJob {
+        steps: u32,
+    }
+
+    #[async_trait]
+    impl Job for ProgressJob {
+        type JobTypeData = MockJobType;
+
+        async fn run(&self, job_data: &Self::JobTypeData, context: &JobContext) -> Result<(), JobRunError> {
+            for step in 1..=self.steps {
+                let percent = step as f32 * 100.0 / self.steps as f32;
+                context.set_progress(percent, Some(&format!("Step {}", step))).await.unwrap();
+            }
+            job_data.shared_data.lock().await.push(format!("{:?}", context.metadata().progress));
+            Ok(())
+        }
+    }
+
+    #[job(MockJobType)]
+    struct ResumableJob {}
+
+    #[async_trait]
+    impl Job for ResumableJob {
+        type JobTypeData = MockJobType;
+
+        async fn run(&self, job_data: &Self::JobTypeData, context: &JobContext) -> Result<(), JobRunError> {
+            match context.checkpoint::<u32>().unwrap() {
+                Some(step) => {
+                    job_data.shared_data.lock().await.push(format!("Resumed at {}", step));
+                    Ok(())
+                }
+                None => {
+                    context.save_checkpoint(&3).await.unwrap();
+                    Err(JobRunError::new("Interrupted"))
+                }
+            }
+        }
+
+        fn max_attempts(&self) -> u32 {
+            2
+        }
+    }
+
+    #[job(MockJobType)]
+    struct StalledJob {}
+
+    #[async_trait]
+    impl Job for StalledJob {
+        type JobTypeData = MockJobType;
+
+        async fn run(&self, job_data: &Self::JobTypeData, context: &JobContext) -> Result<(), JobRunError> {
+            match context.checkpoint::<u32>().unwrap() {
+                Some(step) => {
+                    job_data.shared_data.lock().await.push(format!("Resumed at {}", step));
+                    Ok(())
+                }
+                None => {
+                    context.save_checkpoint(&5).await.unwrap();
+                    std::future::pending().await
+                }
+            }
         }
     }
 
@@ -117,7 +437,9 @@ mod tests {
     #[async_trait]
     impl Job for OtherJob {
         type JobTypeData = OtherJobType;
-        async fn run(&self, _: &Self::JobTypeData) {}
+        async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            Ok(())
+        }
     }
 
     #[tokio::test]
@@ -140,7 +462,7 @@ mod tests {
             },
         );
 
-        let mut executor = executor.start().await;
+        let mut executor = executor.start();
         executor.wait_for(2, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
 
         assert_eq!(*shared_data.lock().await, vec![
@@ -148,4 +470,965 @@ mod tests {
             "MSG2: Hello, world!"
         ]);
     }
+
+    #[tokio::test]
+    async fn prefetch_runs_all_jobs() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        queue.push_jobs(&[
+            &MockJob { msg: "a".to_string() },
+            &MockJob2 { msg: "b".to_string() },
+            &MockJob { msg: "c".to_string() },
+        ]).await.unwrap();
+
+        let shared_data = Arc::new(Mutex::new(Vec::new()));
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: shared_data.clone(),
+            },
+        ).with_prefetch(2);
+
+        let mut executor = executor.start();
+        executor.wait_for(3, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        executor.stop().await.unwrap();
+
+        assert_eq!(*shared_data.lock().await, vec![
+            "MSG: Hello, a",
+            "MSG2: Hello, b",
+            "MSG: Hello, c",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn failed_jobs_are_retried_then_dead_lettered() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        let job_meta = queue.push_job(&FailingJob { msg: "a".to_string() }).await.unwrap();
+
+        let shared_data = Arc::new(Mutex::new(Vec::new()));
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: shared_data.clone(),
+            },
+        );
+
+        let mut executor = executor.start();
+        executor.wait_for(2, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        executor.stop().await.unwrap();
+
+        assert_eq!(*shared_data.lock().await, vec!["FAIL: Hello, a", "FAIL: Hello, a"]);
+        assert_eq!(queue.get_job(job_meta.uid).await.unwrap().state, JobState::Failed);
+
+        let dead_letters = queue.list_dead_letters().await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].attempts, 2);
+        assert_eq!(dead_letters[0].errors.len(), 2);
+
+        let job_meta = queue.requeue_dead_letter(job_meta.uid, None).await.unwrap();
+        assert_eq!(job_meta.state, JobState::NotStarted);
+        assert_eq!(job_meta.attempts, 0);
+        assert!(queue.list_dead_letters().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn attempts_are_recorded() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        let job_meta = queue.push_job(&FailingJob { msg: "a".to_string() }).await.unwrap();
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: Arc::new(Mutex::new(Vec::new())),
+            },
+        ).with_worker_id("worker-1");
+
+        let mut executor = executor.start();
+        executor.wait_for(2, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        executor.stop().await.unwrap();
+
+        let attempts = queue.list_attempts(job_meta.uid).await.unwrap();
+        assert_eq!(attempts.iter().map(|attempt| attempt.attempt).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(attempts.iter().all(|attempt| attempt.worker_id == "worker-1"
+            && attempt.started <= attempt.finished
+            && attempt.error == Some(JobRunError::new("FAIL: Hello, a"))));
+
+        queue.purge_dead_letters(&[job_meta.uid]).await.unwrap();
+        assert!(queue.list_attempts(job_meta.uid).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn executor_prunes_finished_jobs() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        let jobs_meta = queue.push_jobs(&[
+            &MockJob { msg: "a".to_string() },
+            &MockJob { msg: "b".to_string() },
+        ]).await.unwrap();
+
+        let executor = Executor::new(
+            storage_provider.clone(),
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: Arc::new(Mutex::new(Vec::new())),
+            },
+        ).with_retention(storage_provider.clone(), RetentionPolicy {
+            completed: Retention { max_age: None, max_count: Some(1) },
+            archive: true,
+            interval: Duration::from_millis(10),
+            ..Default::default()
+        });
+
+        let mut executor = executor.start();
+        executor.wait_for(2, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        executor.stop().await.unwrap();
+
+        assert!(queue.get_job(jobs_meta[0].uid).await.is_err());
+        assert_eq!(queue.get_job(jobs_meta[1].uid).await.unwrap().state, JobState::Completed);
+
+        let archived = storage_provider.archived_jobs().unwrap();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].metadata.uid, jobs_meta[0].uid);
+        assert_eq!(archived[0].data["msg"], "a");
+    }
+
+    #[tokio::test]
+    async fn list_jobs_filters_and_paginates() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        let jobs_meta = queue.push_jobs(&[
+            &FailingJob { msg: "a".to_string() },
+            &MockJob { msg: "b".to_string() },
+            &MockJob { msg: "c".to_string() },
+        ]).await.unwrap();
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: Arc::new(Mutex::new(Vec::new())),
+            },
+        );
+
+        let mut executor = executor.start();
+        executor.wait_for(4, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        executor.stop().await.unwrap();
+
+        let failed = queue.list_jobs(&JobFilter { has_error: Some(true), ..Default::default() }).await.unwrap();
+        assert_eq!(failed.jobs.len(), 1);
+        assert_eq!(failed.jobs[0].uid, jobs_meta[0].uid);
+        assert_eq!(failed.jobs[0].state, JobState::Failed);
+
+        let filter = JobFilter { states: vec![JobState::Completed], limit: 1, ..Default::default() };
+        let first_page = queue.list_jobs(&filter).await.unwrap();
+        let second_page = queue.list_jobs(&JobFilter { after: first_page.next_cursor, ..filter.clone() }).await.unwrap();
+        assert_eq!(first_page.jobs.len(), 1);
+        assert_eq!(second_page.jobs.len(), 1);
+        assert!(second_page.next_cursor.is_none());
+        assert!(first_page.jobs[0].uid < second_page.jobs[0].uid);
+
+        let zero_limit_page = queue.list_jobs(&JobFilter { limit: 0, ..filter }).await.unwrap();
+        assert_eq!(zero_limit_page.jobs.len(), 1);
+        assert_eq!(zero_limit_page.next_cursor, first_page.next_cursor);
+    }
+
+    #[tokio::test]
+    async fn stats_count_jobs_per_state() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        queue.push_job(&FailingJob { msg: "a".to_string() }).await.unwrap();
+        queue.push_job(&MockJob { msg: "b".to_string() }).await.unwrap();
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: Arc::new(Mutex::new(Vec::new())),
+            },
+        );
+
+        let mut executor = executor.start();
+        executor.wait_for(3, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        executor.stop().await.unwrap();
+
+        queue.push_job(&MockJob { msg: "c".to_string() }).await.unwrap();
+
+        let stats = queue.stats(Duration::from_secs(60)).await.unwrap();
+        let job_stats = &stats.job_types["MockJobType"];
+        assert_eq!(job_stats.counts[&JobState::Failed], 1);
+        assert_eq!(job_stats.counts[&JobState::Completed], 1);
+        assert_eq!(job_stats.counts[&JobState::NotStarted], 1);
+        assert!(job_stats.oldest_pending_age.is_some());
+        assert_eq!(job_stats.completed_in_window, 1);
+        assert_eq!(job_stats.failed_in_window, 1);
+    }
+
+    struct RecordingMiddleware {
+        name: &'static str,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Middleware<dyn MockJobTypeMarker> for RecordingMiddleware {
+        async fn on_push(&self, _: &dyn MockJobTypeMarker, _: &JobMetadata) {
+            self.events.lock().await.push(format!("{} push", self.name));
+        }
+
+        async fn before_run(&self, _: &JobInfo<dyn MockJobTypeMarker>) -> Result<(), JobRunError> {
+            self.events.lock().await.push(format!("{} before", self.name));
+            Ok(())
+        }
+
+        async fn after_run(&self, _: &JobInfo<dyn MockJobTypeMarker>, result: &Result<(), JobRunError>) {
+            self.events.lock().await.push(format!("{} after {}", self.name, result.is_ok()));
+        }
+
+        async fn on_failure(&self, _: &JobInfo<dyn MockJobTypeMarker>, _: &JobRunError) {
+            self.events.lock().await.push(format!("{} failure", self.name));
+        }
+    }
+
+    #[tokio::test]
+    async fn middleware_runs_in_order() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let mut queue = Queue::new(storage_provider.clone())
+            .with_middleware(RecordingMiddleware { name: "a", events: events.clone() })
+            .with_middleware(RecordingMiddleware { name: "b", events: events.clone() });
+
+        queue.push_job(&MockJob { msg: "a".to_string() }).await.unwrap();
+        let job_meta = queue.push_job(&FailingJob { msg: "b".to_string() }).await.unwrap();
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: Arc::new(Mutex::new(Vec::new())),
+            },
+        )
+            .with_middleware(RecordingMiddleware { name: "a", events: events.clone() })
+            .with_middleware(RecordingMiddleware { name: "b", events: events.clone() });
+
+        let mut executor = executor.start();
+        executor.wait_for(3, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        executor.stop().await.unwrap();
+
+        let failed_attempt = [
+            "a before", "b before", "b failure", "b after false", "a failure", "a after false",
+        ];
+        let mut expected = vec![
+            "a push", "b push", "a push", "b push",
+            "a before", "b before", "b after true", "a after true",
+        ];
+        expected.extend(failed_attempt);
+        expected.extend(failed_attempt);
+
+        assert_eq!(*events.lock().await, expected);
+        assert_eq!(queue.get_job(job_meta.uid).await.unwrap().state, JobState::Failed);
+    }
+
+    struct RejectingMiddleware;
+
+    #[async_trait]
+    impl Middleware<dyn MockJobTypeMarker> for RejectingMiddleware {
+        async fn before_run(&self, _: &JobInfo<dyn MockJobTypeMarker>) -> Result<(), JobRunError> {
+            Err(JobRunError::new("Rejected"))
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_jobs_only_unwind_entered_middleware() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let shared_data = Arc::new(Mutex::new(Vec::new()));
+
+        let mut queue = Queue::new(storage_provider.clone());
+        let job_meta = queue.push_job(&MockJob { msg: "a".to_string() }).await.unwrap();
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: shared_data.clone(),
+            },
+        )
+            .with_middleware(RecordingMiddleware { name: "a", events: events.clone() })
+            .with_middleware(RejectingMiddleware)
+            .with_middleware(RecordingMiddleware { name: "b", events: events.clone() });
+
+        let mut executor = executor.start();
+        executor.wait_for(1, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        executor.stop().await.unwrap();
+
+        assert_eq!(*events.lock().await, vec!["a before", "a failure", "a after false"]);
+        assert!(shared_data.lock().await.is_empty());
+        assert_eq!(queue.get_job(job_meta.uid).await.unwrap().state, JobState::Failed);
+    }
+
+    #[tokio::test]
+    async fn events_follow_job_lifecycle() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+        let mut pushed = queue.subscribe();
+
+        let ok_meta = queue.push_job(&MockJob { msg: "a".to_string() }).await.unwrap();
+        let failing_meta = queue.push_job(&FailingJob { msg: "b".to_string() }).await.unwrap();
+
+        assert!(matches!(pushed.next().await, Some(JobEvent::Pushed(x)) if x.uid == ok_meta.uid));
+        assert!(matches!(pushed.next().await, Some(JobEvent::Pushed(x)) if x.uid == failing_meta.uid));
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: Arc::new(Mutex::new(Vec::new())),
+            },
+        );
+        let mut events = executor.subscribe();
+        let executor = executor.start();
+
+        let mut received = Vec::new();
+        while received.len() < 6 {
+            let event = tokio::time::timeout(Duration::from_millis(200), events.next()).await
+                .expect("Failed waiting for events")
+                .unwrap();
+            let kind = match &event {
+                JobEvent::Started(_) => "started",
+                JobEvent::Completed(_) => "completed",
+                JobEvent::Retried(_) => "retried",
+                JobEvent::Failed(_) => "failed",
+                _ => "other",
+            };
+            received.push((event.metadata().uid, kind));
+        }
+        executor.stop().await.unwrap();
+
+        assert_eq!(received, vec![
+            (ok_meta.uid, "started"),
+            (ok_meta.uid, "completed"),
+            (failing_meta.uid, "started"),
+            (failing_meta.uid, "retried"),
+            (failing_meta.uid, "started"),
+            (failing_meta.uid, "failed"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn pool_runs_executors_for_every_job_type() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let other_storage_provider = InMemoryStorageProvider::<dyn OtherJobTypeMarker>::default();
+
+        let mut queue = Queue::new(storage_provider.clone());
+        let mut other_queue = Queue::new(other_storage_provider.clone());
+
+        queue.push_jobs(&[&MockJob { msg: "a".to_string() }, &MockJob { msg: "b".to_string() }])
+            .await.unwrap();
+        let other_meta = other_queue.push_job(&OtherJob { msg: "c".to_string() }).await.unwrap();
+
+        let shared_data = Arc::new(Mutex::new(Vec::new()));
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: shared_data.clone(),
+            },
+        );
+        let other_executor = Executor::new(other_storage_provider, OtherJobType {});
+        let mut other_events = other_executor.subscribe();
+
+        let pool = ExecutorPool::new()
+            .with_concurrency_limit(1)
+            .register(executor)
+            .register(other_executor)
+            .start();
+
+        let health = pool.health();
+        assert!(health.is_healthy());
+        assert_eq!(health.executors.len(), 2);
+        assert_eq!(health.executors[1].job_type, "OtherJobType");
+
+        loop {
+            let event = tokio::time::timeout(Duration::from_millis(200), other_events.next()).await
+                .expect("Failed waiting for events")
+                .unwrap();
+            if let JobEvent::Completed(metadata) = event {
+                assert_eq!(metadata.uid, other_meta.uid);
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        pool.stop().await.unwrap();
+
+        assert_eq!(*shared_data.lock().await, vec!["MSG: Hello, a", "MSG: Hello, b"]);
+    }
+
+    #[tokio::test]
+    async fn pool_only_pulls_jobs_it_can_run() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+        let other_storage_provider = InMemoryStorageProvider::<dyn OtherJobTypeMarker>::default();
+        let mut other_queue = Queue::new(other_storage_provider.clone());
+
+        let stalled_meta = queue.push_job(&StalledJob {}).await.unwrap();
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: Arc::new(Mutex::new(Vec::new())),
+            },
+        );
+        let pool = ExecutorPool::new()
+            .with_concurrency_limit(1)
+            .register(executor)
+            .register(Executor::new(other_storage_provider, OtherJobType {}))
+            .start();
+
+        tokio::time::timeout(Duration::from_millis(200), async {
+            while queue.get_job(stalled_meta.uid).await.unwrap().checkpoint.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("Job didn't start");
+
+        let other_meta = other_queue.push_job(&OtherJob { msg: "a".to_string() }).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(other_queue.get_job(other_meta.uid).await.unwrap().state, JobState::NotStarted);
+
+        pool.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rate_limit_delays_jobs() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        queue.push_jobs(&[
+            &MockJob { msg: "a".to_string() },
+            &MockJob { msg: "b".to_string() },
+            &MockJob { msg: "c".to_string() },
+        ]).await.unwrap();
+
+        let shared_data = Arc::new(Mutex::new(Vec::new()));
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: shared_data.clone(),
+            },
+        ).with_rate_limit(RateLimit { limit: 2, period: Duration::from_secs(3600) });
+
+        let mut executor = executor.start();
+        executor.wait_for(2, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        assert!(executor.wait_for(1, Duration::from_millis(100)).await.is_err());
+        executor.stop().await.unwrap();
+
+        assert_eq!(*shared_data.lock().await, vec!["MSG: Hello, a", "MSG: Hello, b"]);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_keys_dont_hold_up_other_jobs() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        queue.push_jobs(&[
+            &RateLimitedJob { key: "a".to_string() },
+            &RateLimitedJob { key: "a".to_string() },
+            &RateLimitedJob { key: "b".to_string() },
+        ]).await.unwrap();
+
+        let shared_data = Arc::new(Mutex::new(Vec::new()));
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: shared_data.clone(),
+            },
+        ).with_prefetch(3).with_rate_limit(RateLimit { limit: 1, period: Duration::from_secs(3600) });
+
+        let mut executor = executor.start();
+        executor.wait_for(2, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        assert!(executor.wait_for(1, Duration::from_millis(100)).await.is_err());
+        executor.stop().await.unwrap();
+
+        assert_eq!(*shared_data.lock().await, vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn old_job_versions_are_migrated() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        let old_meta = storage_provider
+            .push_stored(serde_json::json!({ "type": "MigratedJob", "msg": "a" }), 1)
+            .await.unwrap();
+        let job_meta = queue.push_job(&MigratedJob { messages: vec!["b".to_string()] }).await.unwrap();
+        assert_eq!(job_meta.version, 2);
+
+        let shared_data = Arc::new(Mutex::new(Vec::new()));
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: shared_data.clone(),
+            },
+        );
+
+        let mut executor = executor.start();
+        executor.wait_for(2, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        executor.stop().await.unwrap();
+
+        assert_eq!(*shared_data.lock().await, vec!["a", "b"]);
+        assert_eq!(queue.get_job(old_meta.uid).await.unwrap().state, JobState::Completed);
+    }
+
+    #[tokio::test]
+    async fn concurrency_key_limits_running_jobs() {
+        let mut storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+
+        let jobs_meta = storage_provider.push_many(&[
+            &TenantJob { tenant: "a".to_string() },
+            &TenantJob { tenant: "a".to_string() },
+            &TenantJob { tenant: "b".to_string() },
+        ]).await.unwrap();
+
+        let pulled: Vec<_> = storage_provider.pull_many(3).await.unwrap().into_iter()
+            .map(|job_info| job_info.metadata.uid)
+            .collect();
+        assert_eq!(pulled, vec![jobs_meta[0].uid, jobs_meta[2].uid]);
+
+        storage_provider.set_job_result(jobs_meta[0].uid, Ok(())).await.unwrap();
+        assert_eq!(storage_provider.pull().await.unwrap().metadata.uid, jobs_meta[1].uid);
+    }
+
+    #[tokio::test]
+    async fn undeserializable_jobs_are_poisoned() {
+        let mut storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+
+        let poisoned_meta = storage_provider
+            .push_stored(serde_json::json!({ "type": "MissingJob" }), 1)
+            .await.unwrap();
+        let job_meta = storage_provider.push(&MockJob { msg: "a".to_string() }).await.unwrap();
+
+        let pulled: Vec<_> = storage_provider.pull_many(2).await.unwrap().into_iter()
+            .map(|job_info| job_info.metadata.uid)
+            .collect();
+        assert_eq!(pulled, vec![job_meta.uid]);
+
+        let poisoned = storage_provider.get_job(poisoned_meta.uid).await.unwrap();
+        assert_eq!(poisoned.state, JobState::Poisoned);
+        assert_eq!(poisoned.attempts, 0);
+        let error = poisoned.result.expect("Poisoned job has no error");
+        assert_eq!(error.code.as_deref(), Some(JobRunError::DESERIALIZATION_CODE));
+        assert!(!error.retryable);
+        assert_eq!(poisoned.errors, vec![error]);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_dead_lettered_straight_away() {
+        let mut storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+
+        let job_meta = storage_provider.push(&FailingJob { msg: "a".to_string() }).await.unwrap();
+        assert_eq!(job_meta.max_attempts, 2);
+        storage_provider.pull().await.unwrap();
+
+        let error = JobRunError::new("Invalid input").permanent();
+        let failed = storage_provider.set_job_result(job_meta.uid, Err(error.clone())).await.unwrap();
+        assert_eq!(failed.state, JobState::Failed);
+        assert_eq!(failed.attempts, 1);
+        assert!(storage_provider.try_pull().await.unwrap().is_none());
+
+        let dead_letter = storage_provider.get_dead_letter(job_meta.uid).await.unwrap();
+        assert_eq!(dead_letter.errors, vec![error]);
+    }
+
+    #[tokio::test]
+    async fn progress_is_saved() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        let job_meta = queue.push_job(&ProgressJob { steps: 4 }).await.unwrap();
+        assert_eq!(job_meta.progress, None);
+
+        let shared_data = Arc::new(Mutex::new(Vec::new()));
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: shared_data.clone(),
+            },
+        ).with_progress_interval(Duration::from_secs(3600));
+
+        let mut executor = executor.start();
+        executor.wait_for(1, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        executor.stop().await.unwrap();
+
+        // The job only sees metadata from when it started
+        assert_eq!(*shared_data.lock().await, vec!["None"]);
+        assert_eq!(queue.get_job(job_meta.uid).await.unwrap().progress, Some(JobProgress {
+            percent: 100.0,
+            message: Some("Step 4".to_string()),
+        }));
+    }
+
+    #[tokio::test]
+    async fn retries_resume_from_checkpoint() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        let job_meta = queue.push_job(&ResumableJob {}).await.unwrap();
+
+        let shared_data = Arc::new(Mutex::new(Vec::new()));
+
+        let executor = Executor::new(
+            storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: shared_data.clone(),
+            },
+        );
+
+        let mut executor = executor.start();
+        executor.wait_for(2, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        executor.stop().await.unwrap();
+
+        assert_eq!(*shared_data.lock().await, vec!["Resumed at 3"]);
+        assert_eq!(queue.get_job(job_meta.uid).await.unwrap().state, JobState::Completed);
+    }
+
+    #[tokio::test]
+    async fn stopping_releases_the_running_job() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        let job_meta = queue.push_job(&StalledJob {}).await.unwrap();
+
+        let shared_data = Arc::new(Mutex::new(Vec::new()));
+        let job_type = || MockJobType {
+            data_msg_type: "Hello".to_string(),
+            shared_data: shared_data.clone(),
+        };
+
+        let executor = Executor::new(storage_provider.clone(), job_type()).start();
+        tokio::time::timeout(Duration::from_millis(200), async {
+            while queue.get_job(job_meta.uid).await.unwrap().checkpoint.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("Job didn't save a checkpoint");
+        executor.stop().await.unwrap();
+
+        let released = queue.get_job(job_meta.uid).await.unwrap();
+        assert_eq!(released.state, JobState::NotStarted);
+        assert_eq!(released.attempts, 0);
+
+        let mut executor = Executor::new(storage_provider, job_type()).start();
+        executor.wait_for(1, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        executor.stop().await.unwrap();
+
+        assert_eq!(*shared_data.lock().await, vec!["Resumed at 5"]);
+        assert_eq!(queue.get_job(job_meta.uid).await.unwrap().state, JobState::Completed);
+    }
+
+    // Paused time makes backoff deterministic, the clock only moves once every task is waiting
+    #[tokio::test(start_paused = true)]
+    async fn transient_storage_errors_are_retried() {
+        let storage_provider = InMemoryStorageProvider::<dyn MockJobTypeMarker>::default();
+        let mut queue = Queue::new(storage_provider.clone());
+
+        queue.push_job(&MockJob { msg: "a".to_string() }).await.unwrap();
+
+        let shared_data = Arc::new(Mutex::new(Vec::new()));
+
+        let flaky_storage_provider = FlakyStorageProvider::new(storage_provider, vec![
+            StorageError::Unavailable("Connection reset".to_string()),
+            StorageError::Unavailable("Connection reset".to_string()),
+        ]);
+        let executor = Executor::new(
+            flaky_storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: shared_data.clone(),
+            },
+        ).with_storage_retry_policy(StorageRetryPolicy {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            failure_threshold: 2,
+            open_duration: Duration::from_millis(50),
+        });
+
+        let mut executor = executor.start();
+        // The circuit opens after the second failure, 10ms in, and closes 50ms later
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(executor.is_circuit_open());
+
+        executor.wait_for(1, Duration::from_millis(200)).await.expect("Failed waiting for jobs to finish");
+        assert!(!executor.is_circuit_open());
+        assert!(executor.is_running());
+        executor.stop().await.unwrap();
+
+        assert_eq!(*shared_data.lock().await, vec!["MSG: Hello, a"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permanent_storage_errors_stop_the_executor() {
+        let flaky_storage_provider = FlakyStorageProvider::new(
+            InMemoryStorageProvider::<dyn MockJobTypeMarker>::default(),
+            vec![StorageError::Unspecified("Table missing".to_string())],
+        );
+        let executor = Executor::new(
+            flaky_storage_provider,
+            MockJobType {
+                data_msg_type: "Hello".to_string(),
+                shared_data: Arc::new(Mutex::new(Vec::new())),
+            },
+        );
+
+        let executor = executor.start();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!executor.is_running());
+        assert!(matches!(
+            executor.stop().await,
+            Err(ExecutionError::Storage(StorageError::Unspecified(_))),
+        ));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn job_spans_continue_the_pushing_trace() {
+        use opentelemetry::{global, sdk, trace::{TraceContextExt, TracerProvider}};
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        global::set_text_map_propagator(sdk::propagation::TraceContextPropagator::new());
+        // Tracers only hold a weak reference to their provider, which has to outlive the test
+        let provider = sdk::trace::TracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let mut queue = Queue::new(InMemoryStorageProvider::<dyn MockJobTypeMarker>::default());
+        let push_span = tracing::info_span!("push");
+        let job_meta = {
+            let _entered = push_span.enter();
+            queue.push_job(&MockJob { msg: "a".to_string() }).await.unwrap()
+        };
+        assert!(job_meta.trace_context.contains_key("traceparent"));
+
+        let job_span = crate::telemetry::job_span(&job_meta);
+        assert_eq!(
+            job_span.context().span().span_context().trace_id(),
+            push_span.context().span().span_context().trace_id(),
+        );
+    }
+
+    #[tokio::test]
+    async fn in_memory_storage_conformance() {
+        let storage_provider = InMemoryStorageProvider::default();
+        crate::testing::run_all(|| storage_provider.clone()).await;
+    }
+
+    #[cfg(feature = "metrics")]
+    mod instrumentation {
+        use std::{collections::HashMap, sync::{atomic::Ordering, Arc, Mutex, OnceLock}};
+
+        use async_trait::async_trait;
+        use metrics::{
+            atomics::AtomicU64, Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Recorder,
+            SharedString, Unit,
+        };
+        use tokio::time::Duration;
+
+        use crate::{
+            job, job_type, Executor, Job, JobContext, JobRunError, Queue, StorageProvider,
+            storage::{InMemoryStorageProvider, Retention, RetentionPolicy},
+        };
+
+        #[derive(Default)]
+        struct Samples(Mutex<Vec<f64>>);
+
+        impl HistogramFn for Samples {
+            fn record(&self, value: f64) {
+                self.0.lock().unwrap().push(value);
+            }
+        }
+
+        /// Keeps every metric it's handed, named like `name{label=value,...}`
+        #[derive(Default)]
+        struct TestRecorder {
+            values: Mutex<HashMap<String, Arc<AtomicU64>>>,
+            histograms: Mutex<HashMap<String, Arc<Samples>>>,
+        }
+
+        /// Recorders are process wide, so every test shares the one installed first
+        fn recorder() -> &'static TestRecorder {
+            static RECORDER: OnceLock<&'static TestRecorder> = OnceLock::new();
+            RECORDER.get_or_init(|| {
+                let recorder: &'static TestRecorder = Box::leak(Box::default());
+                metrics::set_recorder(recorder).unwrap();
+                recorder
+            })
+        }
+
+        fn metric_name(key: &Key) -> String {
+            let labels: Vec<_> = key.labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect();
+            format!("{}{{{}}}", key.name(), labels.join(","))
+        }
+
+        impl TestRecorder {
+            fn register(&self, key: &Key) -> Arc<AtomicU64> {
+                self.values.lock().unwrap().entry(metric_name(key)).or_default().clone()
+            }
+
+            fn counter(&self, name: &str) -> u64 {
+                let values = self.values.lock().unwrap();
+                values.get(name).map_or(0, |value| value.load(Ordering::Acquire))
+            }
+
+            fn gauge(&self, name: &str) -> Option<f64> {
+                let values = self.values.lock().unwrap();
+                values.get(name).map(|value| f64::from_bits(value.load(Ordering::Acquire)))
+            }
+
+            fn samples(&self, name: &str) -> Vec<f64> {
+                let histograms = self.histograms.lock().unwrap();
+                histograms.get(name).map_or_else(Vec::new, |samples| samples.0.lock().unwrap().clone())
+            }
+        }
+
+        impl Recorder for TestRecorder {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+            fn register_counter(&self, key: &Key) -> Counter {
+                Counter::from_arc(self.register(key))
+            }
+
+            fn register_gauge(&self, key: &Key) -> Gauge {
+                Gauge::from_arc(self.register(key))
+            }
+
+            fn register_histogram(&self, key: &Key) -> Histogram {
+                let mut histograms = self.histograms.lock().unwrap();
+                Histogram::from_arc(histograms.entry(metric_name(key)).or_default().clone())
+            }
+        }
+
+        // A job type of its own keeps other tests out of the counts
+        #[job_type]
+        struct MeteredJobType {}
+
+        #[job(MeteredJobType)]
+        struct MeteredJob {
+            fail: bool,
+        }
+
+        #[async_trait]
+        impl Job for MeteredJob {
+            type JobTypeData = MeteredJobType;
+
+            async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+                match self.fail {
+                    true => Err(JobRunError::new("Failed")),
+                    false => Ok(()),
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn jobs_are_counted() {
+            let recorder = recorder();
+            let storage_provider = InMemoryStorageProvider::<dyn MeteredJobTypeMarker>::default();
+            let mut queue = Queue::new(storage_provider.clone());
+
+            // The executor waits for the jobs first, which doesn't count towards pull latency
+            let mut executor = Executor::new(storage_provider, MeteredJobType {}).start();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            queue.push_jobs(&[
+                &MeteredJob { fail: false },
+                &MeteredJob { fail: true },
+            ]).await.unwrap();
+            executor.wait_for(2, Duration::from_millis(200)).await
+                .expect("Failed waiting for jobs to finish");
+            executor.stop().await.unwrap();
+            queue.stats(Duration::from_secs(60)).await.unwrap();
+
+            let labels = "{job_type=MeteredJobType}";
+            assert_eq!(recorder.counter(&format!("ajobqueue_jobs_pushed_total{}", labels)), 2);
+            assert_eq!(recorder.counter(&format!("ajobqueue_jobs_pulled_total{}", labels)), 2);
+            assert_eq!(recorder.counter(&format!("ajobqueue_jobs_completed_total{}", labels)), 1);
+            assert_eq!(recorder.counter(&format!("ajobqueue_jobs_failed_total{}", labels)), 1);
+            let depth = "ajobqueue_queue_depth{job_type=MeteredJobType";
+            assert_eq!(recorder.gauge(&format!("{},state=completed}}", depth)), Some(1.0));
+            assert_eq!(recorder.gauge(&format!("{},state=failed}}", depth)), Some(1.0));
+            assert_eq!(recorder.gauge(&format!("{},state=running}}", depth)), Some(0.0));
+
+            let pulls = recorder.samples(&format!("ajobqueue_pull_duration_seconds{}", labels));
+            assert!(pulls.iter().all(|latency| *latency < 0.05), "Pull latencies {:?}", pulls);
+        }
+
+        #[job_type]
+        struct DrainedJobType {}
+
+        #[job(DrainedJobType)]
+        struct DrainedJob {}
+
+        #[async_trait]
+        impl Job for DrainedJob {
+            type JobTypeData = DrainedJobType;
+
+            async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+                Ok(())
+            }
+        }
+
+        #[tokio::test]
+        async fn queue_depth_drops_to_zero() {
+            let recorder = recorder();
+            let mut storage_provider = InMemoryStorageProvider::<dyn DrainedJobTypeMarker>::default();
+            let mut queue = Queue::new(storage_provider.clone());
+            let depth = |state: &str| {
+                recorder.gauge(&format!("ajobqueue_queue_depth{{job_type=DrainedJobType,state={}}}", state))
+            };
+
+            let job_meta = queue.push_job(&DrainedJob {}).await.unwrap();
+            queue.stats(Duration::from_secs(60)).await.unwrap();
+            assert_eq!(depth("not-started"), Some(1.0));
+
+            storage_provider.pull().await.unwrap();
+            storage_provider.set_job_result(job_meta.uid, Ok(())).await.unwrap();
+            queue.stats(Duration::from_secs(60)).await.unwrap();
+            assert_eq!(depth("not-started"), Some(0.0));
+            assert_eq!(depth("completed"), Some(1.0));
+
+            let retention = RetentionPolicy {
+                completed: Retention { max_age: Some(Duration::ZERO), max_count: None },
+                ..Default::default()
+            };
+            storage_provider.prune(&retention).await.unwrap();
+            let stats = queue.stats(Duration::from_secs(60)).await.unwrap();
+            assert!(!stats.job_types.contains_key("DrainedJobType"));
+            assert_eq!(depth("completed"), Some(0.0));
+        }
+    }
 }
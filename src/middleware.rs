@@ -0,0 +1,28 @@
+use async_trait::async_trait;
+
+use crate::{
+    storage::{JobInfo, JobMetadata},
+    JobRunError, JobTypeMarker,
+};
+
+/// Hooks around pushing and running jobs, registered with `Queue::with_middleware` and
+/// `Executor::with_middleware`. Hooks before an action are called in the order the middleware was
+/// registered, hooks after it in reverse order.
+#[async_trait]
+pub trait Middleware<J: JobTypeMarker + ?Sized>: Send + Sync {
+    /// Called after a job was pushed to storage
+    async fn on_push(&self, _job: &J, _metadata: &JobMetadata) {}
+
+    /// Called before a job runs. Returning an error fails the attempt without running the job,
+    /// later middleware is skipped. Only middleware whose `before_run` succeeded gets `on_failure`
+    /// and `after_run` afterwards.
+    async fn before_run(&self, _job_info: &JobInfo<J>) -> Result<(), JobRunError> {
+        Ok(())
+    }
+
+    /// Called after every attempt, whether it succeeded or not
+    async fn after_run(&self, _job_info: &JobInfo<J>, _result: &Result<(), JobRunError>) {}
+
+    /// Called when an attempt fails, before `after_run`
+    async fn on_failure(&self, _job_info: &JobInfo<J>, _error: &JobRunError) {}
+}
@@ -0,0 +1,112 @@
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+use crate::error::ExecutionError;
+use crate::{Executor, JobTypeMarker, RunningExecutor};
+
+/// Type erased executor, so executors for different job types can be managed together
+trait PoolMember: Send {
+    fn start(self: Box<Self>, permits: Option<Arc<Semaphore>>) -> RunningExecutor;
+}
+
+impl<J: JobTypeMarker + ?Sized + 'static> PoolMember for Executor<J> {
+    fn start(self: Box<Self>, permits: Option<Arc<Semaphore>>) -> RunningExecutor {
+        self.with_permits(permits).start()
+    }
+}
+
+/// Runs executors for any number of job types, starting and stopping them together
+#[derive(Default)]
+pub struct ExecutorPool {
+    executors: Vec<Box<dyn PoolMember>>,
+    concurrency_limit: Option<usize>,
+}
+
+impl ExecutorPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<J: JobTypeMarker + ?Sized + 'static>(mut self, executor: Executor<J>) -> Self {
+        self.executors.push(Box::new(executor));
+        self
+    }
+
+    /// Limit how many jobs run at once across every executor in the pool. Executors only pull jobs
+    /// they have room to run, so jobs over the limit stay in storage for any executor to pick up.
+    /// Executors look for new jobs once a second instead of waiting on storage for them.
+    pub fn with_concurrency_limit(mut self, concurrency_limit: usize) -> Self {
+        self.concurrency_limit = Some(concurrency_limit.max(1));
+        self
+    }
+
+    pub fn start(self) -> RunningExecutorPool {
+        let permits = self.concurrency_limit.map(|limit| Arc::new(Semaphore::new(limit)));
+
+        RunningExecutorPool {
+            executors: self.executors.into_iter()
+                .map(|executor| executor.start(permits.clone()))
+                .collect(),
+        }
+    }
+}
+
+pub struct RunningExecutorPool {
+    executors: Vec<RunningExecutor>,
+}
+
+impl RunningExecutorPool {
+    pub fn executors(&self) -> &[RunningExecutor] {
+        &self.executors
+    }
+
+    pub fn health(&self) -> PoolHealth {
+        PoolHealth {
+            executors: self.executors.iter()
+                .map(|executor| ExecutorHealth {
+                    job_type: executor.job_type().to_string(),
+                    running: executor.is_running(),
+                    circuit_open: executor.is_circuit_open(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Stop every executor, returning the first error once all of them stopped. Every executor is
+    /// signaled before waiting for any, so they wind down at the same time.
+    pub async fn stop(self) -> Result<(), ExecutionError> {
+        for executor in &self.executors {
+            executor.signal_stop();
+        }
+
+        let mut result = Ok(());
+        for executor in self.executors {
+            let stopped = executor.join().await;
+            if result.is_ok() {
+                result = stopped;
+            }
+        }
+        result
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolHealth {
+    pub executors: Vec<ExecutorHealth>,
+}
+
+impl PoolHealth {
+    /// Whether every executor in the pool is still running and can reach storage
+    pub fn is_healthy(&self) -> bool {
+        self.executors.iter().all(|executor| executor.running && !executor.circuit_open)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ExecutorHealth {
+    pub job_type: String,
+    pub running: bool,
+    /// Storage failed repeatedly and the executor is waiting before trying it again
+    pub circuit_open: bool,
+}
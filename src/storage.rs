@@ -1,9 +1,16 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::time::Instant;
+
 use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
 use ulid::Ulid;
 
 use crate::{
     error::{JobRunError, StorageError},
-    JobTypeMarker,
+    JobType, JobTypeMarker,
 };
 
 mod in_memory;
@@ -15,7 +22,7 @@ pub use postgres::PostgresStorageProvider;
 
 pub use in_memory::InMemoryStorageProvider;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "postgres", derive(sqlx::Type))]
 #[cfg_attr(feature = "postgres", sqlx(type_name = "job_state"))]
 #[cfg_attr(feature = "postgres", sqlx(rename_all = "kebab-case"))]
@@ -23,14 +30,200 @@ pub enum JobState {
     NotStarted,
     Running,
     Completed,
-    Failed
+    Failed,
+    /// The job's stored data couldn't be deserialized, it's kept aside and never run
+    Poisoned,
+}
+
+impl JobState {
+    /// Every state, in the order jobs move through them
+    pub const ALL: [JobState; 5] = [
+        JobState::NotStarted,
+        JobState::Running,
+        JobState::Completed,
+        JobState::Failed,
+        JobState::Poisoned,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::NotStarted => "not-started",
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::Failed => "failed",
+            JobState::Poisoned => "poisoned",
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct JobMetadata {
     pub uid: Ulid,
+    pub job_type: String,
     pub state: JobState,
     pub result: Option<JobRunError>,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    /// Errors from every failed attempt, oldest first
+    pub errors: Vec<JobRunError>,
+    pub created: DateTime<Utc>,
+    pub started: Option<DateTime<Utc>>,
+    pub completed: Option<DateTime<Utc>>,
+    /// Trace context of the span that pushed the job, only captured with the `tracing` feature
+    pub trace_context: HashMap<String, String>,
+    pub concurrency_key: Option<String>,
+    pub concurrency_limit: u32,
+    /// Last progress reported through `JobContext::set_progress`
+    pub progress: Option<JobProgress>,
+    /// Last state saved through `JobContext::save_checkpoint`, dropped when the job is
+    /// dead-lettered
+    pub checkpoint: Option<Value>,
+    /// Version of the job's payload when it was pushed
+    pub version: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobProgress {
+    pub percent: f32,
+    pub message: Option<String>,
+}
+
+/// A job that failed on every one of its attempts and was moved out of the queue
+#[derive(Clone, Debug)]
+pub struct DeadLetter {
+    pub uid: Ulid,
+    pub job_type: String,
+    pub data: Value,
+    pub errors: Vec<JobRunError>,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    pub created: DateTime<Utc>,
+    pub dead_lettered: DateTime<Utc>,
+    pub concurrency_key: Option<String>,
+    pub concurrency_limit: u32,
+    pub version: u32,
+}
+
+impl DeadLetter {
+    pub fn to_job_metadata(&self) -> JobMetadata {
+        JobMetadata {
+            uid: self.uid,
+            job_type: self.job_type.clone(),
+            state: JobState::Failed,
+            result: self.errors.last().cloned(),
+            attempts: self.attempts,
+            max_attempts: self.max_attempts,
+            errors: self.errors.clone(),
+            created: self.created,
+            started: None,
+            completed: Some(self.dead_lettered),
+            trace_context: HashMap::new(),
+            concurrency_key: self.concurrency_key.clone(),
+            concurrency_limit: self.concurrency_limit,
+            progress: None,
+            checkpoint: None,
+            version: self.version,
+        }
+    }
+}
+
+/// A finished job moved out of storage by pruning with `RetentionPolicy::archive` set, along with
+/// its payload
+#[derive(Clone, Debug)]
+pub struct ArchivedJob {
+    pub metadata: JobMetadata,
+    pub data: Value,
+    pub archived: DateTime<Utc>,
+}
+
+/// Narrows down the jobs returned by `StorageProvider::list_jobs`, unset fields match every job.
+/// Dead-lettered jobs are listed with the failed state.
+#[derive(Clone, Debug)]
+pub struct JobFilter {
+    /// Match any of these states, or every state when empty
+    pub states: Vec<JobState>,
+    /// Only list jobs of this type. Each job type is its own queue, so this is the queue filter.
+    pub job_type: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub started_after: Option<DateTime<Utc>>,
+    pub started_before: Option<DateTime<Utc>>,
+    /// Whether any attempt of the job failed
+    pub has_error: Option<bool>,
+    /// Only list jobs after this uid, pass the previous page's `next_cursor` to continue listing
+    pub after: Option<Ulid>,
+    /// Most jobs per page, a limit of 0 is treated as 1 so listing can always move on
+    pub limit: usize,
+}
+
+impl Default for JobFilter {
+    fn default() -> Self {
+        Self {
+            states: Vec::new(),
+            job_type: None,
+            created_after: None,
+            created_before: None,
+            started_after: None,
+            started_before: None,
+            has_error: None,
+            after: None,
+            limit: 100,
+        }
+    }
+}
+
+impl JobFilter {
+    pub(crate) fn matches(&self, metadata: &JobMetadata) -> bool {
+        (self.states.is_empty() || self.states.contains(&metadata.state))
+            && self.job_type.as_ref().is_none_or(|job_type| *job_type == metadata.job_type)
+            && self.created_after.is_none_or(|after| metadata.created > after)
+            && self.created_before.is_none_or(|before| metadata.created < before)
+            && self.started_after.is_none_or(|after| metadata.started.is_some_and(|x| x > after))
+            && self.started_before.is_none_or(|before| metadata.started.is_some_and(|x| x < before))
+            && self.has_error.is_none_or(|has_error| has_error != metadata.errors.is_empty())
+            && self.after.is_none_or(|after| metadata.uid > after)
+    }
+
+    /// `limit`, raised to at least one job per page
+    pub(crate) fn page_size(&self) -> usize {
+        self.limit.max(1)
+    }
+}
+
+/// One run of a job, recorded by the executor that ran it
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobAttempt {
+    pub job_uid: Ulid,
+    /// Starts at 1 for the first attempt
+    pub attempt: u32,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    /// Executor that ran the attempt, see `Executor::with_worker_id`
+    pub worker_id: String,
+    /// Why the attempt failed, `None` when it succeeded
+    pub error: Option<JobRunError>,
+}
+
+/// A page of jobs ordered by uid
+#[derive(Clone, Debug)]
+pub struct JobPage {
+    pub jobs: Vec<JobMetadata>,
+    /// Set when there are more jobs to list
+    pub next_cursor: Option<Ulid>,
+}
+
+impl JobPage {
+    /// Build a page out of up to `limit + 1` matching jobs, the extra job signals another page
+    pub(crate) fn new(mut jobs: Vec<JobMetadata>, limit: usize) -> Self {
+        let next_cursor = if jobs.len() > limit {
+            jobs.truncate(limit);
+            jobs.last().map(|job| job.uid)
+        } else {
+            None
+        };
+
+        Self { jobs, next_cursor }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -39,13 +232,240 @@ pub struct JobInfo<J: JobTypeMarker + ?Sized> {
     pub job: Box<J>,
 }
 
+/// Statistics for a single job type, see `StorageProvider::stats`
+#[derive(Clone, Debug, Default)]
+pub struct JobTypeStats {
+    /// Number of jobs in each state, dead-lettered jobs count as failed
+    pub counts: HashMap<JobState, u64>,
+    /// How long the oldest job that hasn't started yet has been waiting
+    pub oldest_pending_age: Option<Duration>,
+    /// Average time between creating and starting jobs, for jobs started within the window
+    pub average_latency: Option<Duration>,
+    pub completed_in_window: u64,
+    pub failed_in_window: u64,
+    /// Completed jobs per second over the window
+    pub throughput: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct QueueStats {
+    pub window: Duration,
+    pub job_types: HashMap<String, JobTypeStats>,
+}
+
+impl QueueStats {
+    pub(crate) fn new(window: Duration, mut job_types: HashMap<String, JobTypeStats>) -> Self {
+        for stats in job_types.values_mut() {
+            stats.throughput = if window.is_zero() {
+                0.0
+            } else {
+                stats.completed_in_window as f64 / window.as_secs_f64()
+            };
+        }
+
+        Self { window, job_types }
+    }
+}
+
+/// Limits on how many finished jobs are kept. Jobs are pruned once they're older than `max_age`
+/// or fall outside the `max_count` most recently finished jobs, whichever comes first.
+#[derive(Clone, Debug, Default)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_count: Option<u64>,
+}
+
+impl Retention {
+    pub(crate) fn cutoff(&self, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, StorageError> {
+        self.max_age
+            .map(|max_age| chrono::Duration::from_std(max_age).map(|max_age| now - max_age))
+            .transpose()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    pub completed: Retention,
+    /// Applies to dead-lettered jobs
+    pub failed: Retention,
+    /// Move pruned jobs to an archive instead of deleting them
+    pub archive: bool,
+    /// How often the executor prunes storage
+    pub interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            completed: Retention::default(),
+            failed: Retention::default(),
+            archive: false,
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Deserialize a stored job, accepting the previous names of jobs registered as aliases and
+/// upgrading payloads stored at an older `version` with the registered migrations
+pub(crate) fn deserialize_job<J: JobTypeMarker + ?Sized>(
+    mut data: Value, version: u32,
+) -> Result<Box<J>, serde_json::Error>
+where
+    Box<J>: DeserializeOwned,
+{
+    let job_type = J::job_type();
+    let name = match data.get_mut("type") {
+        Some(tag) => {
+            let alias = crate::JOB_ALIASES.iter()
+                .find(|alias| Some(alias.alias) == tag.as_str() && (alias.job_type)() == job_type);
+            if let Some(alias) = alias {
+                *tag = Value::from(alias.name);
+            }
+            tag.as_str().map(String::from)
+        }
+        None => None,
+    };
+
+    if let Some(name) = name {
+        let mut version = version;
+        while let Some(migration) = crate::JOB_MIGRATIONS.iter().find(|migration| {
+            migration.from == version && migration.name == name && (migration.job_type)() == job_type
+        }) {
+            data = (migration.migrate)(data)?;
+            // Migrations may rebuild the payload without its tag
+            if let Some(object) = data.as_object_mut() {
+                object.entry("type").or_insert_with(|| Value::from(name.as_str()));
+            }
+            version += 1;
+        }
+    }
+
+    serde_json::from_value(data)
+}
+
+/// Trace context to store with newly pushed jobs
+pub(crate) fn current_trace_context() -> HashMap<String, String> {
+    #[cfg(feature = "tracing")]
+    {
+        crate::telemetry::current_context()
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        HashMap::new()
+    }
+}
+
+/// Allow at most `limit` jobs to start within each `period`. Periods are fixed windows, so up to
+/// twice the limit may start around the boundary between two windows.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    pub limit: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    /// Start and end of the window `now` falls in
+    pub(crate) fn window(&self, now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let period = (self.period.as_millis() as i64).max(1);
+        let start = now.timestamp_millis() - now.timestamp_millis().rem_euclid(period);
+        let start = Utc.timestamp_millis_opt(start).unwrap();
+        (start, start + chrono::Duration::milliseconds(period))
+    }
+}
+
+/// How often `StorageProvider::pull_with_timeout` checks for jobs by default
+const PULL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Call `try_pull` every `interval` until it returns a job or `timeout` has passed
+pub(crate) async fn poll_try_pull<J, S>(
+    storage: &mut S,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<Option<JobInfo<J>>, StorageError>
+where
+    J: JobTypeMarker + ?Sized,
+    S: StorageProvider<J> + ?Sized,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(job_info) = storage.try_pull().await? {
+            return Ok(Some(job_info));
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        tokio::time::sleep(interval.min(deadline - now)).await;
+    }
+}
+
 // TODO - try to type erase like erased_serde
 // This would allow StorageProvider to work for all Job types with a single instantiation
 #[async_trait]
 pub trait StorageProvider<J: JobTypeMarker + ?Sized>: Send + Sync {
     async fn push(&mut self, job: &J) -> Result<JobMetadata, StorageError>;
-    async fn pull(&mut self) -> Result<JobInfo<J>, StorageError>;
+    /// Push several jobs at once. Either every job is stored, or none are.
+    async fn push_many(&mut self, jobs: &[&J]) -> Result<Vec<JobMetadata>, StorageError>;
+    /// Pull the next job and mark it as running, waiting for as long as it takes for one to be
+    /// available. Calls `pull_with_timeout` by default, so a provider implementing
+    /// `pull_with_timeout` through `pull` must implement `pull` as well, or the two call each
+    /// other forever.
+    async fn pull(&mut self) -> Result<JobInfo<J>, StorageError> {
+        loop {
+            if let Some(job_info) = self.pull_with_timeout(Duration::from_secs(60)).await? {
+                return Ok(job_info);
+            }
+        }
+    }
+    /// Pull the next job and mark it as running, or return `None` straight away when no job is
+    /// available.
+    async fn try_pull(&mut self) -> Result<Option<JobInfo<J>>, StorageError>;
+    /// Pull the next job and mark it as running, waiting up to `timeout` for one to be available.
+    /// Polls `try_pull` once a second by default, providers that can be notified of new jobs
+    /// should override it.
+    async fn pull_with_timeout(&mut self, timeout: Duration) -> Result<Option<JobInfo<J>>, StorageError> {
+        poll_try_pull(self, timeout, PULL_POLL_INTERVAL).await
+    }
+    /// Pull up to `count` available jobs at once, marking every returned job as running. Doesn't
+    /// wait for jobs, so fewer or none are returned when not enough are available.
+    async fn pull_many(&mut self, count: usize) -> Result<Vec<JobInfo<J>>, StorageError>;
+    /// Hand pulled jobs that were never run back to the queue so other executors can pick them up.
+    async fn release(&mut self, uids: &[Ulid]) -> Result<(), StorageError>;
+    /// Record the outcome of an attempt. Failed jobs with attempts left are queued again, jobs
+    /// which exhausted their attempts are moved to the dead-letter area.
     async fn set_job_result(&mut self, uid: Ulid, job_result: Result<(), JobRunError>)
         -> Result<JobMetadata, StorageError>;
+    async fn set_progress(&mut self, uid: Ulid, progress: &JobProgress) -> Result<(), StorageError>;
+    /// Store state for later attempts of the job to resume from
+    async fn save_checkpoint(&mut self, uid: Ulid, checkpoint: &Value) -> Result<(), StorageError>;
+    /// Add an attempt to the history of its job
+    async fn record_attempt(&mut self, attempt: &JobAttempt) -> Result<(), StorageError>;
+    /// Every recorded attempt of a job, oldest first. Attempts are kept until the job is purged
+    /// or pruned without being archived.
+    async fn list_attempts(&self, job_id: Ulid) -> Result<Vec<JobAttempt>, StorageError>;
     async fn get_job(&self, job_id: Ulid) -> Result<JobMetadata, StorageError>;
+    async fn list_jobs(&self, filter: &JobFilter) -> Result<JobPage, StorageError>;
+    /// Job counts per type and state, along with latency and throughput over the last `window`
+    async fn stats(&self, window: Duration) -> Result<QueueStats, StorageError>;
+
+    async fn list_dead_letters(&self) -> Result<Vec<DeadLetter>, StorageError>;
+    async fn get_dead_letter(&self, job_id: Ulid) -> Result<DeadLetter, StorageError>;
+    /// Move a dead-lettered job back into the queue with its attempts reset, optionally replacing
+    /// its payload. Checkpoints aren't kept through the dead-letter area, so the requeued job
+    /// starts from scratch.
+    async fn requeue_dead_letter(&mut self, job_id: Ulid, job: Option<&J>)
+        -> Result<JobMetadata, StorageError>;
+    /// Permanently delete dead-lettered jobs, returning how many were removed.
+    async fn purge_dead_letters(&mut self, job_ids: &[Ulid]) -> Result<u64, StorageError>;
+
+    /// Delete or archive completed and dead-lettered jobs that fall outside of the retention
+    /// policy, returning how many were pruned.
+    async fn prune(&mut self, retention: &RetentionPolicy) -> Result<u64, StorageError>;
+
+    /// Take a slot from the rate limit for `key`, shared by everything using the same storage.
+    /// Returns how long to wait before trying again when the limit is reached.
+    async fn acquire_rate_limit(&mut self, key: &str, rate_limit: &RateLimit)
+        -> Result<Option<Duration>, StorageError>;
 }
@@ -1,20 +1,43 @@
-use std::{marker::PhantomData, sync::{RwLock, Arc}, collections::HashMap};
+use std::{marker::PhantomData, sync::{RwLock, Arc}, collections::HashMap, time::Duration};
 
 use async_channel::{unbounded, Receiver, Sender};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use serde::de::DeserializeOwned;
+use serde_json::Value;
 use ulid::Ulid;
 
-use super::{StorageProvider, JobMetadata, JobState, JobInfo};
+use super::{
+    current_trace_context, deserialize_job, ArchivedJob, StorageProvider, DeadLetter, JobAttempt,
+    JobFilter, JobMetadata, JobPage, JobState, JobInfo, JobTypeStats, JobProgress, QueueStats,
+    RateLimit, Retention, RetentionPolicy,
+};
 use crate::{
     error::{JobRunError, StorageError},
-    JobTypeMarker,
+    JobType, JobTypeMarker,
 };
 
+struct InMemoryJob {
+    metadata: JobMetadata,
+    data: String,
+}
+
+/// Slots taken from a rate limit in its current window
+struct RateLimitWindow {
+    start: DateTime<Utc>,
+    count: u32,
+}
+
 // PhantomData necessary so struct only impls one generic impl of StorageProvider
 pub struct InMemoryStorageProvider<J: JobTypeMarker + ?Sized> {
-    job_queue: (Sender<(Ulid, String)>, Receiver<(Ulid, String)>),
-    jobs: Arc<RwLock<HashMap<Ulid, JobMetadata>>>,
+    job_queue: (Sender<Ulid>, Receiver<Ulid>),
+    jobs: Arc<RwLock<HashMap<Ulid, InMemoryJob>>>,
+    dead_letters: Arc<RwLock<HashMap<Ulid, DeadLetter>>>,
+    archived: Arc<RwLock<Vec<ArchivedJob>>>,
+    attempts: Arc<RwLock<HashMap<Ulid, Vec<JobAttempt>>>>,
+    rate_limits: Arc<RwLock<HashMap<String, RateLimitWindow>>>,
+    /// Pulled jobs put aside until a job with the same concurrency key stops running
+    blocked: Arc<RwLock<Vec<Ulid>>>,
     _phantom_data: PhantomData<J>,
 }
 
@@ -23,6 +46,11 @@ impl<J: JobTypeMarker + ?Sized> Clone for InMemoryStorageProvider<J> {
         Self {
             job_queue: self.job_queue.clone(),
             jobs: self.jobs.clone(),
+            dead_letters: self.dead_letters.clone(),
+            archived: self.archived.clone(),
+            attempts: self.attempts.clone(),
+            rate_limits: self.rate_limits.clone(),
+            blocked: self.blocked.clone(),
             _phantom_data: PhantomData,
         }
     }
@@ -33,74 +61,632 @@ impl<J: JobTypeMarker + ?Sized> Default for InMemoryStorageProvider<J> {
         InMemoryStorageProvider {
             job_queue: unbounded(),
             jobs: Arc::new(RwLock::new(HashMap::new())),
+            dead_letters: Arc::new(RwLock::new(HashMap::new())),
+            archived: Arc::new(RwLock::new(Vec::new())),
+            attempts: Arc::new(RwLock::new(HashMap::new())),
+            rate_limits: Arc::new(RwLock::new(HashMap::new())),
+            blocked: Arc::new(RwLock::new(Vec::new())),
             _phantom_data: PhantomData,
         }
     }
 }
 
-#[async_trait]
-impl<J: JobTypeMarker + ?Sized> StorageProvider<J> for InMemoryStorageProvider<J>
+impl<J: JobTypeMarker + ?Sized> InMemoryStorageProvider<J> {
+    /// Jobs moved out of storage by pruning with `RetentionPolicy::archive` set
+    pub fn archived_jobs(&self) -> Result<Vec<ArchivedJob>, StorageError> {
+        Ok(self.archived.read()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .clone())
+    }
+
+    /// Queue `data` as if a job had been stored at `version`, without going through a `Job`
+    #[cfg(test)]
+    pub(crate) async fn push_stored(&self, data: Value, version: u32) -> Result<JobMetadata, StorageError> {
+        let metadata = JobMetadata {
+            uid: Ulid::new(),
+            job_type: J::job_type(),
+            state: JobState::NotStarted,
+            result: None,
+            attempts: 0,
+            max_attempts: 1,
+            errors: Vec::new(),
+            created: Utc::now(),
+            started: None,
+            completed: None,
+            trace_context: HashMap::new(),
+            concurrency_key: None,
+            concurrency_limit: 1,
+            progress: None,
+            checkpoint: None,
+            version,
+        };
+
+        self.jobs.write()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .insert(metadata.uid, InMemoryJob { metadata: metadata.clone(), data: data.to_string() });
+        self.queue_jobs(vec![metadata.uid]).await?;
+
+        Ok(metadata)
+    }
+
+    /// Take back jobs blocked on `concurrency_key` after a job with that key stopped running, they
+    /// need to be queued again once the locks are released.
+    fn unblock(
+        &self,
+        jobs: &HashMap<Ulid, InMemoryJob>,
+        concurrency_key: &Option<String>,
+    ) -> Result<Vec<Ulid>, StorageError> {
+        if concurrency_key.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let mut blocked = self.blocked.write()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+        let (unblocked, still_blocked) = blocked.iter().partition(|uid| {
+            jobs.get(uid).is_none_or(|stored_job| stored_job.metadata.concurrency_key == *concurrency_key)
+        });
+        *blocked = still_blocked;
+
+        Ok(unblocked)
+    }
+
+    async fn queue_jobs(&self, uids: Vec<Ulid>) -> Result<(), StorageError> {
+        for uid in uids {
+            self.job_queue.0.send(uid).await
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+impl<J: JobTypeMarker + ?Sized> InMemoryStorageProvider<J>
 where Box<J>: DeserializeOwned
 {
-    async fn pull(&mut self) -> Result<JobInfo<J>, StorageError> {
-        let (uid, serialized_job) = self.job_queue.1.recv().await
+    /// Mark a job as running, or put it aside when its concurrency key is at its limit. Jobs that
+    /// can't be deserialized are marked as poisoned instead.
+    fn start_job(&self, uid: Ulid) -> Result<Option<JobInfo<J>>, StorageError> {
+        let mut jobs = self.jobs.write()
             .map_err(|x| StorageError::Unspecified(x.to_string()))?;
-        let job: Box<J> = serde_json::from_str(&serialized_job)?;
+        let metadata = &jobs.get(&uid)
+            .ok_or_else(|| StorageError::Unspecified(format!("Uid not found: {}", uid)))?
+            .metadata;
 
+        if let Some(concurrency_key) = &metadata.concurrency_key {
+            let running = jobs.values()
+                .filter(|stored_job| stored_job.metadata.state == JobState::Running
+                    && stored_job.metadata.concurrency_key.as_ref() == Some(concurrency_key))
+                .count();
+            if running >= metadata.concurrency_limit as usize {
+                self.blocked.write()
+                    .map_err(|x| StorageError::Unspecified(x.to_string()))?
+                    .push(uid);
+                return Ok(None);
+            }
+        }
 
-        let jobs = self.jobs.read()
-            .map_err(|x| StorageError::Unspecified(x.to_string()))?;
-        let metadata = jobs.get(&uid)
+        let stored_job = jobs.get_mut(&uid)
             .ok_or_else(|| StorageError::Unspecified(format!("Uid not found: {}", uid)))?;
+        let job = serde_json::from_str(&stored_job.data)
+            .and_then(|data| deserialize_job::<J>(data, stored_job.metadata.version));
+        let job = match job {
+            Ok(job) => job,
+            Err(err) => {
+                log::error!("Poisoned job {}: {}", uid, err);
+                let error = JobRunError::deserialization(err.to_string());
+                stored_job.metadata.state = JobState::Poisoned;
+                stored_job.metadata.result = Some(error.clone());
+                stored_job.metadata.errors.push(error);
+                stored_job.metadata.completed = Some(Utc::now());
+                return Ok(None);
+            }
+        };
+        stored_job.metadata.state = JobState::Running;
+        stored_job.metadata.attempts += 1;
+        stored_job.metadata.started = Some(Utc::now());
 
-        Ok(JobInfo {
-            metadata: metadata.clone(),
+        Ok(Some(JobInfo {
+            metadata: stored_job.metadata.clone(),
             job,
-        })
+        }))
+    }
+}
+
+#[async_trait]
+impl<J: JobTypeMarker + ?Sized> StorageProvider<J> for InMemoryStorageProvider<J>
+where Box<J>: DeserializeOwned
+{
+    async fn pull(&mut self) -> Result<JobInfo<J>, StorageError> {
+        loop {
+            let uid = self.job_queue.1.recv().await
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+            if let Some(job_info) = self.start_job(uid)? {
+                return Ok(job_info);
+            }
+        }
+    }
+
+    async fn try_pull(&mut self) -> Result<Option<JobInfo<J>>, StorageError> {
+        // Jobs put aside for their concurrency key are skipped
+        while let Ok(uid) = self.job_queue.1.try_recv() {
+            if let Some(job_info) = self.start_job(uid)? {
+                return Ok(Some(job_info));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn pull_with_timeout(&mut self, timeout: Duration) -> Result<Option<JobInfo<J>>, StorageError> {
+        match tokio::time::timeout(timeout, self.pull()).await {
+            Ok(job_info) => Ok(Some(job_info?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn pull_many(&mut self, count: usize) -> Result<Vec<JobInfo<J>>, StorageError> {
+        let mut jobs = Vec::new();
+        while jobs.len() < count {
+            match self.try_pull().await {
+                Ok(Some(job_info)) => jobs.push(job_info),
+                Ok(None) => break,
+                // Jobs pulled so far are already running and would be lost with the batch, the
+                // error comes up again on the next pull
+                Err(err) if !jobs.is_empty() => {
+                    log::error!("Failed to pull more jobs, returning {} pulled: {}", jobs.len(), err);
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(jobs)
+    }
+
+    async fn release(&mut self, uids: &[Ulid]) -> Result<(), StorageError> {
+        let mut released = Vec::with_capacity(uids.len());
+        {
+            let mut jobs = self.jobs.write()
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+            let mut concurrency_keys = Vec::new();
+            for uid in uids {
+                if let Some(stored_job) = jobs.get_mut(uid) {
+                    if stored_job.metadata.state == JobState::Running {
+                        stored_job.metadata.state = JobState::NotStarted;
+                        stored_job.metadata.attempts -= 1;
+                        stored_job.metadata.started = None;
+                        released.push(*uid);
+                        concurrency_keys.push(stored_job.metadata.concurrency_key.clone());
+                    }
+                }
+            }
+            for concurrency_key in concurrency_keys {
+                released.extend(self.unblock(&jobs, &concurrency_key)?);
+            }
+        }
+
+        self.queue_jobs(released).await
     }
 
     async fn push(&mut self, job: &J) -> Result<JobMetadata, StorageError> {
-        let uid = Ulid::new();
-        let metadata = JobMetadata { uid, state: JobState::NotStarted, result: None };
+        let metadata = new_job_metadata(Ulid::new(), job);
+        let uid = metadata.uid;
+        let data = serde_json::to_string(&job)?;
 
         self.jobs.write()
             .map_err(|x| StorageError::Unspecified(x.to_string()))?
-            .insert(uid, metadata.clone());
+            .insert(uid, InMemoryJob { metadata: metadata.clone(), data });
 
-        let serialized_job = serde_json::to_string(&job)?;
-        self.job_queue.0.send((uid, serialized_job)).await
+        self.job_queue.0.send(uid).await
             .map_err(|x| StorageError::Unspecified(x.to_string()))?;
 
         Ok(metadata)
     }
 
+    async fn push_many(&mut self, jobs: &[&J]) -> Result<Vec<JobMetadata>, StorageError> {
+        // Serialize everything up front so a failure leaves no partial batch behind
+        let serialized_jobs = jobs.iter()
+            .map(|job| Ok((new_job_metadata(Ulid::new(), *job), serde_json::to_string(job)?)))
+            .collect::<Result<Vec<_>, StorageError>>()?;
+
+        let mut metadata = Vec::with_capacity(serialized_jobs.len());
+        {
+            let mut stored_jobs = self.jobs.write()
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+            for (job_metadata, data) in serialized_jobs {
+                let stored_job = InMemoryJob { metadata: job_metadata.clone(), data };
+                stored_jobs.insert(job_metadata.uid, stored_job);
+                metadata.push(job_metadata);
+            }
+        }
+
+        for job_metadata in &metadata {
+            self.job_queue.0.send(job_metadata.uid).await
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+        }
+
+        Ok(metadata)
+    }
+
     async fn set_job_result(
         &mut self,
         uid: Ulid,
         job_result: Result<(), JobRunError>,
     ) -> Result<JobMetadata, StorageError> {
-        let mut jobs = self.jobs.write()
-            .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+        let (metadata, requeued) = {
+            let mut jobs = self.jobs.write()
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?;
 
-        let mut metadata = jobs.get_mut(&uid)
-            .ok_or_else(|| StorageError::Unspecified("Uid not found".to_string()))?;
+            let metadata = &mut jobs.get_mut(&uid)
+                .ok_or_else(|| StorageError::Unspecified("Uid not found".to_string()))?
+                .metadata;
 
-        metadata.state = if job_result.is_ok() {
-            JobState::Completed
-        } else {
-            JobState::Failed
+            match job_result {
+                Ok(()) => {
+                    metadata.state = JobState::Completed;
+                    metadata.result = None;
+                    metadata.completed = Some(Utc::now());
+                }
+                Err(err) => {
+                    metadata.state = if err.retryable && metadata.attempts < metadata.max_attempts {
+                        JobState::NotStarted
+                    } else {
+                        JobState::Failed
+                    };
+                    metadata.errors.push(err.clone());
+                    metadata.result = Some(err);
+                }
+            }
+            let metadata = metadata.clone();
+
+            if metadata.state == JobState::Failed {
+                // Anything that can fail happens before the job is removed, so it's never lost
+                let data = serde_json::from_str(&jobs[&uid].data)?;
+                let mut dead_letters = self.dead_letters.write()
+                    .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+                jobs.remove(&uid);
+                let dead_letter = DeadLetter {
+                    uid,
+                    job_type: metadata.job_type.clone(),
+                    data,
+                    errors: metadata.errors.clone(),
+                    attempts: metadata.attempts,
+                    max_attempts: metadata.max_attempts,
+                    created: metadata.created,
+                    dead_lettered: Utc::now(),
+                    concurrency_key: metadata.concurrency_key.clone(),
+                    concurrency_limit: metadata.concurrency_limit,
+                    version: metadata.version,
+                };
+                dead_letters.insert(uid, dead_letter);
+            }
+
+            let mut requeued = self.unblock(&jobs, &metadata.concurrency_key)?;
+            if metadata.state == JobState::NotStarted {
+                requeued.push(uid);
+            }
+
+            (metadata, requeued)
         };
-        metadata.result = job_result.err();
 
-        Ok(metadata.clone())
+        self.queue_jobs(requeued).await?;
+
+        Ok(metadata)
+    }
+
+    async fn set_progress(&mut self, uid: Ulid, progress: &JobProgress) -> Result<(), StorageError> {
+        self.jobs.write()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .get_mut(&uid)
+            .ok_or_else(|| StorageError::Unspecified(format!("Uid not found: {}", uid)))?
+            .metadata.progress = Some(progress.clone());
+        Ok(())
+    }
+
+    async fn save_checkpoint(&mut self, uid: Ulid, checkpoint: &Value) -> Result<(), StorageError> {
+        self.jobs.write()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .get_mut(&uid)
+            .ok_or_else(|| StorageError::Unspecified(format!("Uid not found: {}", uid)))?
+            .metadata.checkpoint = Some(checkpoint.clone());
+        Ok(())
+    }
+
+    async fn record_attempt(&mut self, attempt: &JobAttempt) -> Result<(), StorageError> {
+        self.attempts.write()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .entry(attempt.job_uid)
+            .or_default()
+            .push(attempt.clone());
+        Ok(())
+    }
+
+    async fn list_attempts(&self, job_id: Ulid) -> Result<Vec<JobAttempt>, StorageError> {
+        Ok(self.attempts.read()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .get(&job_id)
+            .cloned()
+            .unwrap_or_default())
     }
 
     async fn get_job(&self, uid: Ulid) -> Result<JobMetadata, StorageError> {
-        let jobs = self.jobs.read()
+        {
+            let jobs = self.jobs.read()
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+            if let Some(stored_job) = jobs.get(&uid) {
+                return Ok(stored_job.metadata.clone());
+            }
+        }
+
+        Ok(self.get_dead_letter(uid).await?.to_job_metadata())
+    }
+
+    async fn list_jobs(&self, filter: &JobFilter) -> Result<JobPage, StorageError> {
+        let mut jobs: Vec<JobMetadata> = self.jobs.read()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .values()
+            .map(|stored_job| &stored_job.metadata)
+            .filter(|metadata| filter.matches(metadata))
+            .cloned()
+            .collect();
+
+        jobs.extend(self.dead_letters.read()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .values()
+            .map(DeadLetter::to_job_metadata)
+            .filter(|metadata| filter.matches(metadata)));
+
+        jobs.sort_by_key(|metadata| metadata.uid);
+        jobs.truncate(filter.page_size() + 1);
+
+        Ok(JobPage::new(jobs, filter.page_size()))
+    }
+
+    async fn stats(&self, window: Duration) -> Result<QueueStats, StorageError> {
+        let now = Utc::now();
+        let cutoff = now - chrono::Duration::from_std(window)
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+
+        let mut job_types: HashMap<String, JobTypeStats> = HashMap::new();
+        let mut latencies: HashMap<String, Vec<Duration>> = HashMap::new();
+
+        for stored_job in self.jobs.read()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .values()
+        {
+            let metadata = &stored_job.metadata;
+            let stats = job_types.entry(metadata.job_type.clone()).or_default();
+            *stats.counts.entry(metadata.state.clone()).or_default() += 1;
+
+            if metadata.state == JobState::NotStarted {
+                let age = (now - metadata.created).to_std().unwrap_or_default();
+                stats.oldest_pending_age = stats.oldest_pending_age.max(Some(age));
+            }
+            if let Some(started) = metadata.started.filter(|started| *started >= cutoff) {
+                latencies.entry(metadata.job_type.clone()).or_default()
+                    .push((started - metadata.created).to_std().unwrap_or_default());
+            }
+            if metadata.state == JobState::Completed
+                && metadata.completed.is_some_and(|completed| completed >= cutoff)
+            {
+                stats.completed_in_window += 1;
+            }
+        }
+
+        for dead_letter in self.dead_letters.read()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .values()
+        {
+            let stats = job_types.entry(dead_letter.job_type.clone()).or_default();
+            *stats.counts.entry(JobState::Failed).or_default() += 1;
+            if dead_letter.dead_lettered >= cutoff {
+                stats.failed_in_window += 1;
+            }
+        }
+
+        for (job_type, latencies) in latencies {
+            if let Some(stats) = job_types.get_mut(&job_type) {
+                stats.average_latency = Some(latencies.iter().sum::<Duration>() / latencies.len() as u32);
+            }
+        }
+
+        Ok(QueueStats::new(window, job_types))
+    }
+
+    async fn list_dead_letters(&self) -> Result<Vec<DeadLetter>, StorageError> {
+        let mut dead_letters: Vec<DeadLetter> = self.dead_letters.read()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .values()
+            .cloned()
+            .collect();
+        dead_letters.sort_by_key(|dead_letter| dead_letter.uid);
+        Ok(dead_letters)
+    }
+
+    async fn get_dead_letter(&self, uid: Ulid) -> Result<DeadLetter, StorageError> {
+        let dead_letters = self.dead_letters.read()
             .map_err(|x| StorageError::Unspecified(x.to_string()))?;
-        let metadata = jobs.get(&uid)
+        let dead_letter = dead_letters.get(&uid)
             .ok_or_else(|| StorageError::Unspecified(format!("Uid not found: {}", uid)))?;
-        Ok(metadata.clone())
+        Ok(dead_letter.clone())
+    }
+
+    async fn requeue_dead_letter(
+        &mut self,
+        uid: Ulid,
+        job: Option<&J>,
+    ) -> Result<JobMetadata, StorageError> {
+        let data = job.map(serde_json::to_string).transpose()?;
+
+        let dead_letter = self.dead_letters.write()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .remove(&uid)
+            .ok_or_else(|| StorageError::Unspecified(format!("Uid not found: {}", uid)))?;
+
+        let metadata = JobMetadata {
+            uid,
+            job_type: dead_letter.job_type,
+            state: JobState::NotStarted,
+            result: None,
+            attempts: 0,
+            max_attempts: job.map_or(dead_letter.max_attempts, |job| job.max_attempts()),
+            errors: dead_letter.errors,
+            created: dead_letter.created,
+            started: None,
+            completed: None,
+            trace_context: current_trace_context(),
+            concurrency_key: job.map_or(dead_letter.concurrency_key, |job| job.concurrency_key()),
+            concurrency_limit: job.map_or(dead_letter.concurrency_limit, |job| job.concurrency_limit()),
+            progress: None,
+            checkpoint: None,
+            version: job.map_or(dead_letter.version, |job| job.job_version()),
+        };
+        let data = match data {
+            Some(data) => data,
+            None => serde_json::to_string(&dead_letter.data)?,
+        };
+
+        self.jobs.write()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?
+            .insert(uid, InMemoryJob { metadata: metadata.clone(), data });
+
+        self.job_queue.0.send(uid).await
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+
+        Ok(metadata)
+    }
+
+    async fn purge_dead_letters(&mut self, uids: &[Ulid]) -> Result<u64, StorageError> {
+        let purged: Vec<_> = {
+            let mut dead_letters = self.dead_letters.write()
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+            uids.iter().filter(|uid| dead_letters.remove(uid).is_some()).collect()
+        };
+
+        let mut attempts = self.attempts.write()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+        for uid in &purged {
+            attempts.remove(uid);
+        }
+
+        Ok(purged.len() as u64)
+    }
+
+    async fn prune(&mut self, retention: &RetentionPolicy) -> Result<u64, StorageError> {
+        let now = Utc::now();
+        let mut pruned = Vec::new();
+
+        {
+            let mut jobs = self.jobs.write()
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+            // Poisoned jobs are marked completed too, but stay around to be looked into
+            let completed = jobs.iter()
+                .filter(|(_, stored_job)| stored_job.metadata.state == JobState::Completed)
+                .filter_map(|(uid, stored_job)| stored_job.metadata.completed.map(|completed| (completed, *uid)))
+                .collect();
+            let expired = expired_jobs(completed, &retention.completed, now)?;
+            // Parsed before removing anything, so a failure doesn't lose jobs
+            let data = expired.iter()
+                .map(|uid| serde_json::from_str(&jobs[uid].data))
+                .collect::<Result<Vec<Value>, _>>()?;
+            for (uid, data) in expired.into_iter().zip(data) {
+                if let Some(stored_job) = jobs.remove(&uid) {
+                    pruned.push(ArchivedJob { metadata: stored_job.metadata, data, archived: now });
+                }
+            }
+        }
+
+        {
+            let mut dead_letters = self.dead_letters.write()
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+            let failed = dead_letters.values()
+                .map(|dead_letter| (dead_letter.dead_lettered, dead_letter.uid))
+                .collect();
+            for uid in expired_jobs(failed, &retention.failed, now)? {
+                if let Some(dead_letter) = dead_letters.remove(&uid) {
+                    let metadata = dead_letter.to_job_metadata();
+                    pruned.push(ArchivedJob { metadata, data: dead_letter.data, archived: now });
+                }
+            }
+        }
+
+        let count = pruned.len() as u64;
+        if retention.archive {
+            self.archived.write()
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?
+                .extend(pruned);
+        } else {
+            let mut attempts = self.attempts.write()
+                .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+            for archived_job in &pruned {
+                attempts.remove(&archived_job.metadata.uid);
+            }
+        }
+
+        Ok(count)
+    }
+
+    async fn acquire_rate_limit(
+        &mut self,
+        key: &str,
+        rate_limit: &RateLimit,
+    ) -> Result<Option<Duration>, StorageError> {
+        let now = Utc::now();
+        let (window_start, window_end) = rate_limit.window(now);
+
+        let mut rate_limits = self.rate_limits.write()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+        let window = rate_limits.entry(key.to_string())
+            .or_insert(RateLimitWindow { start: window_start, count: 0 });
+        if window.start != window_start {
+            window.start = window_start;
+            window.count = 0;
+        }
+
+        if window.count < rate_limit.limit {
+            window.count += 1;
+            Ok(None)
+        } else {
+            Ok(Some((window_end - now).to_std().unwrap_or_default()))
+        }
+    }
+}
+
+/// Pick the jobs to prune out of `(finished, uid)` pairs
+fn expired_jobs(
+    mut finished: Vec<(DateTime<Utc>, Ulid)>,
+    retention: &Retention,
+    now: DateTime<Utc>,
+) -> Result<Vec<Ulid>, StorageError> {
+    let cutoff = retention.cutoff(now)?;
+    finished.sort_by(|a, b| b.cmp(a));
+
+    Ok(finished.into_iter()
+        .enumerate()
+        .filter(|(position, (finished, _))| {
+            retention.max_count.is_some_and(|max_count| *position as u64 >= max_count)
+                || cutoff.is_some_and(|cutoff| *finished < cutoff)
+        })
+        .map(|(_, (_, uid))| uid)
+        .collect())
+}
+
+fn new_job_metadata<J: JobTypeMarker + ?Sized>(uid: Ulid, job: &J) -> JobMetadata {
+    JobMetadata {
+        uid,
+        job_type: J::job_type(),
+        state: JobState::NotStarted,
+        result: None,
+        attempts: 0,
+        max_attempts: job.max_attempts(),
+        errors: Vec::new(),
+        created: Utc::now(),
+        started: None,
+        completed: None,
+        trace_context: current_trace_context(),
+        concurrency_key: job.concurrency_key(),
+        concurrency_limit: job.concurrency_limit(),
+        progress: None,
+        checkpoint: None,
+        version: job.job_version(),
     }
 }
 
@@ -1,9 +1,9 @@
-use std::marker::PhantomData;
+use std::{collections::HashMap, marker::PhantomData, time::Duration};
 
 use async_trait::async_trait;
 use serde::de::DeserializeOwned;
 use serde_json::Value;
-use sqlx::{Pool, Postgres, postgres::PgPoolOptions, types::Uuid};
+use sqlx::{Pool, Postgres, QueryBuilder, Transaction, postgres::PgPoolOptions, types::Uuid};
 use ulid::Ulid;
 use indoc::indoc;
 use chrono::{Utc, DateTime};
@@ -15,11 +15,20 @@ use crate::{
     JobType, JobTypeMarker, StorageProvider,
 };
 
-use super::{JobMetadata, JobState, JobInfo};
+use super::{
+    current_trace_context, deserialize_job, poll_try_pull, DeadLetter, JobAttempt, JobFilter,
+    JobMetadata, JobPage, JobProgress, JobState, JobInfo, JobTypeStats, QueueStats, RateLimit,
+    RetentionPolicy,
+};
+
+/// Advisory lock namespace for the concurrency keys being pulled, paired with the key's hash
+const CONCURRENCY_KEY_LOCK: i32 = 0x616a_6f62;
 
 #[derive(Clone)]
 pub struct PostgresStorageProvider<J: JobTypeMarker + ?Sized> {
     pool: Pool<Postgres>,
+    poll_interval: Duration,
+    lease: Option<Duration>,
     _phantom_data: PhantomData<J>,
 }
 
@@ -27,10 +36,34 @@ impl<J: JobTypeMarker + ?Sized> PostgresStorageProvider<J> {
     pub fn new(pool: Pool<Postgres>) -> Self {
         Self {
             pool,
+            poll_interval: Duration::from_secs(1),
+            lease: None,
             _phantom_data: PhantomData,
         }
     }
 
+    /// How often to check for new jobs while waiting for one, defaults to once a second
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Hand running jobs out again once they've gone `lease` without a progress update or
+    /// checkpoint, so jobs left running by a crashed executor resume from their last checkpoint.
+    /// Jobs that run longer than that without reporting in are run twice. Off by default.
+    pub fn with_lease(mut self, lease: Duration) -> Self {
+        self.lease = Some(lease);
+        self
+    }
+
+    /// When a lease taken now runs out
+    fn lease_expiry(&self, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>, StorageError> {
+        self.lease
+            .map(|lease| chrono::Duration::from_std(lease).map(|lease| now + lease))
+            .transpose()
+            .map_err(|x| StorageError::Unspecified(x.to_string()))
+    }
+
     pub async fn from_options(options: PgConnectOptions) -> Result<Self, sqlx::Error> {
         let pool = PgPoolOptions::new()
             .max_connections(5)
@@ -38,6 +71,61 @@ impl<J: JobTypeMarker + ?Sized> PostgresStorageProvider<J> {
             .await?;
         Ok(Self::new(pool))
     }
+
+    /// Push a job as part of the caller's transaction. The job only becomes visible to executors
+    /// once the transaction commits, and disappears with it if it's rolled back.
+    pub async fn push_in_tx(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        job: &J,
+    ) -> Result<JobMetadata, StorageError> {
+        let mut results = Self::insert_jobs(&mut *tx, &[job]).await?;
+        results.pop().ok_or_else(|| StorageError::Unspecified("Job wasn't inserted".to_string()))
+    }
+
+    async fn insert_jobs<'c, E>(executor: E, jobs: &[&J]) -> Result<Vec<JobMetadata>, StorageError>
+    where
+        E: sqlx::Executor<'c, Database = Postgres>,
+    {
+        if jobs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let job_type = J::job_type();
+        let created = Utc::now();
+        let uids: Vec<Uuid> = jobs.iter().map(|_| Ulid::new().into()).collect();
+        let data = jobs.iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?;
+        let max_attempts: Vec<i32> = jobs.iter().map(|job| job.max_attempts() as i32).collect();
+        let trace_context = serde_json::to_value(current_trace_context())?;
+        let concurrency_keys: Vec<Option<String>> = jobs.iter().map(|job| job.concurrency_key()).collect();
+        let concurrency_limits: Vec<i32> = jobs.iter().map(|job| job.concurrency_limit() as i32).collect();
+        let versions: Vec<i32> = jobs.iter().map(|job| job.job_version() as i32).collect();
+
+        // A single statement is atomic, so either the whole batch is inserted or nothing is
+        let mut results = sqlx::query_as::<_, DbJob>(indoc!{"
+                INSERT INTO job_queue
+                    (uid, type, data, created, max_attempts, trace_context, concurrency_key, concurrency_limit,
+                        version)
+                SELECT uid, $1, data, $2, max_attempts, $6, concurrency_key, concurrency_limit, version
+                FROM UNNEST($3::uuid[], $4::jsonb[], $5::int[], $7::varchar[], $8::int[], $9::int[])
+                    WITH ORDINALITY AS batch (uid, data, max_attempts, concurrency_key, concurrency_limit, version,
+                        position)
+                ORDER BY position
+                RETURNING *
+            "})
+            .bind(job_type).bind(created).bind(uids).bind(data).bind(max_attempts)
+            .bind(trace_context).bind(concurrency_keys).bind(concurrency_limits).bind(versions)
+            .fetch_all(executor).await?;
+
+        // Identity values are assigned in insertion order, RETURNING order isn't guaranteed
+        results.sort_by_key(|job| job.id);
+
+        Ok(results.into_iter()
+            .map(DbJob::into_job_metadata)
+            .collect::<Result<Vec<_>, _>>()?)
+    }
 }
 
 #[async_trait]
@@ -45,50 +133,157 @@ impl<J: JobTypeMarker + ?Sized> StorageProvider<J> for PostgresStorageProvider<J
 where
     Box<J>: DeserializeOwned,
 {
-    async fn pull(&mut self) -> Result<JobInfo<J>, StorageError> {
+    async fn try_pull(&mut self) -> Result<Option<JobInfo<J>>, StorageError> {
+        Ok(self.pull_many(1).await?.pop())
+    }
+
+    async fn pull_with_timeout(&mut self, timeout: Duration) -> Result<Option<JobInfo<J>>, StorageError> {
+        let poll_interval = self.poll_interval;
+        poll_try_pull(self, timeout, poll_interval).await
+    }
+
+    async fn pull_many(&mut self, count: usize) -> Result<Vec<JobInfo<J>>, StorageError> {
         let now = chrono::Utc::now();
-        let result = sqlx::query_as::<_, DbJob>(indoc!{"
+        let lease_expires = self.lease_expiry(now)?;
+        let mut tx = self.pool.begin().await?;
+
+        let job_types = [vec![J::job_type()], J::job_type_aliases()].concat();
+
+        // Running jobs whose lease ran out are pulled again like pending jobs, and no longer count
+        // towards their concurrency key's limit.
+        // Running counts per concurrency key are only accurate while no other executor is pulling
+        // jobs with the same key, so each key is locked for the rest of the transaction. Keys
+        // another executor is pulling are skipped rather than waited for, like locked rows.
+        let keys: Vec<String> = sqlx::query_scalar(indoc!{"
+            WITH candidates AS (
+                SELECT concurrency_key, min(created) AS created
+                FROM job_queue
+                WHERE (state = $1 OR (state = $5 AND lease_expires < $6))
+                    AND type = ANY($2) AND concurrency_key IS NOT NULL
+                GROUP BY concurrency_key
+                HAVING max(concurrency_limit) > (
+                    SELECT count(*)
+                    FROM job_queue AS running
+                    WHERE running.state = $5 AND running.concurrency_key = job_queue.concurrency_key
+                        AND (running.lease_expires IS NULL OR running.lease_expires >= $6)
+                )
+                ORDER BY min(created)
+                LIMIT $3
+            )
+            SELECT concurrency_key
+            FROM candidates
+            WHERE pg_try_advisory_xact_lock($4, hashtext(concurrency_key))
+        "})
+            .bind(JobState::NotStarted)
+            .bind(&job_types)
+            .bind(count as i64)
+            .bind(CONCURRENCY_KEY_LOCK)
+            .bind(JobState::Running)
+            .bind(now)
+            .fetch_all(&mut *tx).await?;
+
+        let mut results = sqlx::query_as::<_, DbJob>(indoc!{"
+            WITH running AS (
+                SELECT concurrency_key, count(*) AS running
+                FROM job_queue
+                WHERE state = $2 AND concurrency_key = ANY($6) AND (lease_expires IS NULL OR lease_expires >= $1)
+                GROUP BY concurrency_key
+            ), keyed AS (
+                SELECT
+                    id, created, concurrency_key, concurrency_limit,
+                    row_number() OVER (PARTITION BY concurrency_key ORDER BY created, id) AS position
+                FROM job_queue
+                WHERE (state = $3 OR (state = $2 AND lease_expires < $1))
+                    AND type = ANY($5) AND concurrency_key = ANY($6)
+            ), keyless AS (
+                SELECT id, created
+                FROM job_queue
+                WHERE (state = $3 OR (state = $2 AND lease_expires < $1))
+                    AND type = ANY($5) AND concurrency_key IS NULL
+                ORDER BY created, id
+                LIMIT $4
+                FOR UPDATE SKIP LOCKED
+            )
             UPDATE job_queue
-            SET state = $2, started = $1
+            SET state = $2, started = $1, attempts = attempts + 1, lease_expires = $7
             WHERE id IN (
                 SELECT id
-                FROM job_queue
-                WHERE state = $3
-                ORDER BY created
-                FOR UPDATE SKIP LOCKED
-                LIMIT 1
+                FROM (
+                    SELECT id, created
+                    FROM keyed
+                    LEFT JOIN running USING (concurrency_key)
+                    WHERE COALESCE(running.running, 0) + keyed.position <= keyed.concurrency_limit
+                    UNION ALL
+                    SELECT id, created
+                    FROM keyless
+                ) AS pending
+                ORDER BY created, id
+                LIMIT $4
             )
             RETURNING *
         "})
             .bind(now)
             .bind(JobState::Running)
             .bind(JobState::NotStarted)
-            .fetch_one(&self.pool).await?;
+            .bind(count as i64)
+            .bind(&job_types)
+            .bind(&keys)
+            .bind(lease_expires)
+            .fetch_all(&mut *tx).await?;
+
+        results.sort_by_key(|job| (job.created, job.id));
+
+        let mut jobs = Vec::with_capacity(results.len());
+        for result in results {
+            let uid = result.uid;
+            match result.into_job_info() {
+                Ok(job_info) => jobs.push(job_info),
+                Err(err) => {
+                    // Failing the whole pull would leave the job running forever, set it aside
+                    log::error!("Poisoned job {}: {}", Ulid::from(uid), err);
+                    let error = serde_json::to_value(JobRunError::deserialization(err.to_string()))?;
+                    sqlx::query(indoc!{"
+                        UPDATE job_queue
+                        SET state = $1, result = $2, errors = errors || jsonb_build_array($2), completed = $3
+                        WHERE uid = $4
+                    "})
+                        .bind(JobState::Poisoned)
+                        .bind(error)
+                        .bind(now)
+                        .bind(uid)
+                        .execute(&mut *tx).await?;
+                }
+            }
+        }
 
-        Ok(result.into_job_info()?)
+        tx.commit().await?;
+
+        Ok(jobs)
     }
 
-    async fn push(&mut self, job: &J) -> Result<JobMetadata, StorageError> {
-        let uid: Uuid = Ulid::new().into();
-        let job_type = J::job_type();
-        let data = serde_json::to_value(&job)?;
-        let created = Utc::now();
+    async fn release(&mut self, uids: &[Ulid]) -> Result<(), StorageError> {
+        let uids: Vec<Uuid> = uids.iter().map(|uid| Uuid::from(*uid)).collect();
 
-        let result = sqlx::query_as::<_, DbJob>(indoc!{"
-                INSERT INTO job_queue
-                    (uid, type, data, created)
-                VALUES
-                    ($1, $2, $3, $4)
-                RETURNING *
-            "})
-            .bind(uid).bind(job_type).bind(data).bind(created)
-            .fetch_one(&self.pool).await?;
+        sqlx::query(indoc!{"
+            UPDATE job_queue
+            SET state = $1, started = NULL, attempts = attempts - 1, lease_expires = NULL
+            WHERE uid = ANY($2) AND state = $3
+        "})
+            .bind(JobState::NotStarted)
+            .bind(uids)
+            .bind(JobState::Running)
+            .execute(&self.pool).await?;
 
-        Ok(JobMetadata {
-            uid: Ulid::from(result.uid),
-            state: result.state,
-            result: result.result.map(serde_json::from_value).transpose()?,
-        })
+        Ok(())
+    }
+
+    async fn push(&mut self, job: &J) -> Result<JobMetadata, StorageError> {
+        let mut results = Self::insert_jobs(&self.pool, &[job]).await?;
+        results.pop().ok_or_else(|| StorageError::Unspecified("Job wasn't inserted".to_string()))
+    }
+
+    async fn push_many(&mut self, jobs: &[&J]) -> Result<Vec<JobMetadata>, StorageError> {
+        Self::insert_jobs(&self.pool, jobs).await
     }
 
     async fn set_job_result(
@@ -96,38 +291,455 @@ where
         uid: Ulid,
         job_result: Result<(), JobRunError>,
     ) -> Result<JobMetadata, StorageError> {
-        let job_state = if job_result.is_ok() {
-            JobState::Completed
-        } else {
-            JobState::Failed
+        let now = Utc::now();
+
+        let err = match job_result {
+            Ok(()) => {
+                let result: DbJob = sqlx::query_as(indoc!{"
+                        UPDATE job_queue
+                        SET result = NULL, state = $1, completed = $2
+                        WHERE uid = $3
+                        RETURNING *
+                    "})
+                    .bind(JobState::Completed)
+                    .bind(now)
+                    .bind(Uuid::from(uid))
+                    .fetch_one(&self.pool).await?;
+
+                return Ok(result.into_job_metadata()?);
+            }
+            Err(err) => err,
         };
-        let job_result = job_result.err().map(serde_json::to_value).transpose()?;
+        let retryable = err.retryable;
+        let err = serde_json::to_value(err)?;
 
+        let mut tx = self.pool.begin().await?;
+
+        // Retryable failures with attempts left go straight back into the queue
         let result: DbJob = sqlx::query_as(indoc!{"
                 UPDATE job_queue
-                SET result = $1, state = $2
-                WHERE uid = $3
+                SET
+                    result = $1,
+                    errors = errors || jsonb_build_array($1),
+                    state = CASE WHEN attempts < max_attempts AND $5 THEN $2 ELSE $3 END
+                WHERE uid = $4
                 RETURNING *
             "})
-            .bind(job_result)
-            .bind(job_state)
+            .bind(err)
+            .bind(JobState::NotStarted)
+            .bind(JobState::Failed)
             .bind(Uuid::from(uid))
-            .fetch_one(&self.pool).await?;
+            .bind(retryable)
+            .fetch_one(&mut *tx).await?;
+
+        if result.state == JobState::Failed {
+            sqlx::query(indoc!{"
+                    WITH dead AS (
+                        DELETE FROM job_queue
+                        WHERE uid = $1
+                        RETURNING *
+                    )
+                    INSERT INTO job_dead_letter
+                        (uid, type, data, errors, attempts, max_attempts, created, dead_lettered,
+                            concurrency_key, concurrency_limit, version)
+                    SELECT uid, type, data, errors, attempts, max_attempts, created, $2,
+                        concurrency_key, concurrency_limit, version
+                    FROM dead
+                "})
+                .bind(Uuid::from(uid))
+                .bind(now)
+                .execute(&mut *tx).await?;
+        }
+
+        tx.commit().await?;
 
         Ok(result.into_job_metadata()?)
     }
 
+    async fn set_progress(&mut self, uid: Ulid, progress: &JobProgress) -> Result<(), StorageError> {
+        // Reporting in renews the job's lease
+        sqlx::query(indoc!{"
+            UPDATE job_queue
+            SET progress = $1, progress_message = $2, lease_expires = COALESCE($4, lease_expires)
+            WHERE uid = $3
+        "})
+            .bind(progress.percent)
+            .bind(&progress.message)
+            .bind(Uuid::from(uid))
+            .bind(self.lease_expiry(Utc::now())?)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn save_checkpoint(&mut self, uid: Ulid, checkpoint: &Value) -> Result<(), StorageError> {
+        sqlx::query(indoc!{"
+            UPDATE job_queue
+            SET checkpoint = $1, lease_expires = COALESCE($3, lease_expires)
+            WHERE uid = $2
+        "})
+            .bind(checkpoint)
+            .bind(Uuid::from(uid))
+            .bind(self.lease_expiry(Utc::now())?)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn record_attempt(&mut self, attempt: &JobAttempt) -> Result<(), StorageError> {
+        sqlx::query(indoc!{"
+            INSERT INTO job_attempt (job_uid, attempt, started, finished, worker_id, error)
+            VALUES ($1, $2, $3, $4, $5, $6)
+        "})
+            .bind(Uuid::from(attempt.job_uid))
+            .bind(attempt.attempt as i32)
+            .bind(attempt.started)
+            .bind(attempt.finished)
+            .bind(&attempt.worker_id)
+            .bind(attempt.error.as_ref().map(serde_json::to_value).transpose()?)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn list_attempts(&self, job_id: Ulid) -> Result<Vec<JobAttempt>, StorageError> {
+        let results = sqlx::query_as::<_, DbJobAttempt>(indoc!{"
+            SELECT *
+            FROM job_attempt
+            WHERE job_uid = $1
+            ORDER BY id
+        "})
+            .bind(Uuid::from(job_id))
+            .fetch_all(&self.pool).await?;
+
+        Ok(results.into_iter()
+            .map(DbJobAttempt::into_job_attempt)
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
     async fn get_job(&self, job_id: Ulid) -> Result<JobMetadata, StorageError> {
         let result = sqlx::query_as::<_, DbJob>(indoc!{"
             SELECT *
             FROM job_queue
             WHERE uid = $1
         "})
-            .bind(&Uuid::from(job_id))
+            .bind(Uuid::from(job_id))
+            .fetch_optional(&self.pool).await?;
+
+        match result {
+            Some(result) => Ok(result.into_job_metadata()?),
+            None => Ok(self.get_dead_letter(job_id).await?.to_job_metadata()),
+        }
+    }
+
+    async fn list_jobs(&self, filter: &JobFilter) -> Result<JobPage, StorageError> {
+        let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM job_listing WHERE TRUE");
+
+        if !filter.states.is_empty() {
+            query.push(" AND state IN (");
+            let mut states = query.separated(", ");
+            for state in &filter.states {
+                states.push_bind(state.clone());
+            }
+            states.push_unseparated(")");
+        }
+        if let Some(job_type) = &filter.job_type {
+            query.push(" AND type = ").push_bind(job_type.clone());
+        }
+        if let Some(created_after) = filter.created_after {
+            query.push(" AND created > ").push_bind(created_after);
+        }
+        if let Some(created_before) = filter.created_before {
+            query.push(" AND created < ").push_bind(created_before);
+        }
+        if let Some(started_after) = filter.started_after {
+            query.push(" AND started > ").push_bind(started_after);
+        }
+        if let Some(started_before) = filter.started_before {
+            query.push(" AND started < ").push_bind(started_before);
+        }
+        if let Some(has_error) = filter.has_error {
+            query.push(" AND (jsonb_array_length(errors) > 0) = ").push_bind(has_error);
+        }
+        if let Some(after) = filter.after {
+            query.push(" AND uid > ").push_bind(Uuid::from(after));
+        }
+        query.push(" ORDER BY uid LIMIT ").push_bind(filter.page_size() as i64 + 1);
+
+        let results = query.build_query_as::<DbJob>()
+            .fetch_all(&self.pool).await?;
+
+        let jobs = results.into_iter()
+            .map(DbJob::into_job_metadata)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(JobPage::new(jobs, filter.page_size()))
+    }
+
+    async fn stats(&self, window: Duration) -> Result<QueueStats, StorageError> {
+        let now = Utc::now();
+        let cutoff = now - chrono::Duration::from_std(window)
+            .map_err(|x| StorageError::Unspecified(x.to_string()))?;
+
+        let mut job_types: HashMap<String, JobTypeStats> = HashMap::new();
+
+        let counts = sqlx::query_as::<_, (String, JobState, i64)>(indoc!{"
+            SELECT type, state, count(*)
+            FROM job_queue
+            GROUP BY type, state
+        "})
+            .fetch_all(&self.pool).await?;
+        for (job_type, state, count) in counts {
+            job_types.entry(job_type).or_default().counts.insert(state, count as u64);
+        }
+
+        let oldest_pending = sqlx::query_as::<_, (String, DateTime<Utc>)>(indoc!{"
+            SELECT type, min(created)
+            FROM job_queue
+            WHERE state = $1
+            GROUP BY type
+        "})
+            .bind(JobState::NotStarted)
+            .fetch_all(&self.pool).await?;
+        for (job_type, created) in oldest_pending {
+            job_types.entry(job_type).or_default().oldest_pending_age =
+                Some((now - created).to_std().unwrap_or_default());
+        }
+
+        let latencies = sqlx::query_as::<_, (String, f64)>(indoc!{"
+            SELECT type, avg(extract(epoch FROM started - created))::float8
+            FROM job_queue
+            WHERE started >= $1
+            GROUP BY type
+        "})
+            .bind(cutoff)
+            .fetch_all(&self.pool).await?;
+        for (job_type, latency) in latencies {
+            job_types.entry(job_type).or_default().average_latency =
+                Some(Duration::from_secs_f64(latency.max(0.0)));
+        }
+
+        let completed = sqlx::query_as::<_, (String, i64)>(indoc!{"
+            SELECT type, count(*)
+            FROM job_queue
+            WHERE state = $1 AND completed >= $2
+            GROUP BY type
+        "})
+            .bind(JobState::Completed)
+            .bind(cutoff)
+            .fetch_all(&self.pool).await?;
+        for (job_type, count) in completed {
+            job_types.entry(job_type).or_default().completed_in_window = count as u64;
+        }
+
+        let dead_letters = sqlx::query_as::<_, (String, i64, i64)>(indoc!{"
+            SELECT type, count(*), count(*) FILTER (WHERE dead_lettered >= $1)
+            FROM job_dead_letter
+            GROUP BY type
+        "})
+            .bind(cutoff)
+            .fetch_all(&self.pool).await?;
+        for (job_type, count, failed_in_window) in dead_letters {
+            let stats = job_types.entry(job_type).or_default();
+            stats.counts.insert(JobState::Failed, count as u64);
+            stats.failed_in_window = failed_in_window as u64;
+        }
+
+        Ok(QueueStats::new(window, job_types))
+    }
+
+    async fn list_dead_letters(&self) -> Result<Vec<DeadLetter>, StorageError> {
+        let results = sqlx::query_as::<_, DbDeadLetter>(indoc!{"
+            SELECT *
+            FROM job_dead_letter
+            ORDER BY uid
+        "})
+            .fetch_all(&self.pool).await?;
+
+        Ok(results.into_iter()
+            .map(DbDeadLetter::into_dead_letter)
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    async fn get_dead_letter(&self, job_id: Ulid) -> Result<DeadLetter, StorageError> {
+        let result = sqlx::query_as::<_, DbDeadLetter>(indoc!{"
+            SELECT *
+            FROM job_dead_letter
+            WHERE uid = $1
+        "})
+            .bind(Uuid::from(job_id))
+            .fetch_one(&self.pool).await?;
+
+        Ok(result.into_dead_letter()?)
+    }
+
+    async fn requeue_dead_letter(
+        &mut self,
+        job_id: Ulid,
+        job: Option<&J>,
+    ) -> Result<JobMetadata, StorageError> {
+        let data = job.map(serde_json::to_value).transpose()?;
+        let max_attempts = job.map(|job| job.max_attempts() as i32);
+        let trace_context = serde_json::to_value(current_trace_context())?;
+        let concurrency_key = job.map(|job| job.concurrency_key());
+        let concurrency_limit = job.map(|job| job.concurrency_limit() as i32);
+        let version = job.map(|job| job.job_version() as i32);
+
+        let result = sqlx::query_as::<_, DbJob>(indoc!{"
+            WITH requeued AS (
+                DELETE FROM job_dead_letter
+                WHERE uid = $1
+                RETURNING *
+            )
+            INSERT INTO job_queue
+                (uid, type, data, created, max_attempts, errors, trace_context, concurrency_key,
+                    concurrency_limit, version)
+            SELECT
+                uid, type, COALESCE($2, data), created, COALESCE($3, max_attempts), errors, $4,
+                CASE WHEN $5 THEN $6 ELSE concurrency_key END, COALESCE($7, concurrency_limit),
+                COALESCE($8, version)
+            FROM requeued
+            RETURNING *
+        "})
+            .bind(Uuid::from(job_id))
+            .bind(data)
+            .bind(max_attempts)
+            .bind(trace_context)
+            .bind(concurrency_key.is_some())
+            .bind(concurrency_key.flatten())
+            .bind(concurrency_limit)
+            .bind(version)
             .fetch_one(&self.pool).await?;
 
         Ok(result.into_job_metadata()?)
     }
+
+    async fn purge_dead_letters(&mut self, job_ids: &[Ulid]) -> Result<u64, StorageError> {
+        let uids: Vec<Uuid> = job_ids.iter().map(|uid| Uuid::from(*uid)).collect();
+
+        let purged: i64 = sqlx::query_scalar(indoc!{"
+            WITH purged AS (
+                DELETE FROM job_dead_letter
+                WHERE uid = ANY($1)
+                RETURNING uid
+            ), attempts AS (
+                DELETE FROM job_attempt
+                WHERE job_uid IN (SELECT uid FROM purged)
+            )
+            SELECT count(*) FROM purged
+        "})
+            .bind(uids)
+            .fetch_one(&self.pool).await?;
+
+        Ok(purged as u64)
+    }
+
+    async fn prune(&mut self, retention: &RetentionPolicy) -> Result<u64, StorageError> {
+        let now = Utc::now();
+        let job_types = [vec![J::job_type()], J::job_type_aliases()].concat();
+
+        // NULL cutoffs and limits match nothing, so unset limits never prune anything
+        let completed: i64 = sqlx::query_scalar(indoc!{"
+            WITH pruned AS (
+                DELETE FROM job_queue
+                WHERE state = $1 AND type = ANY($6) AND (
+                    completed < $2
+                    OR id NOT IN (
+                        SELECT id
+                        FROM job_queue
+                        WHERE state = $1 AND type = ANY($6)
+                        ORDER BY completed DESC, id DESC
+                        LIMIT $3
+                    )
+                )
+                RETURNING *
+            ), archived AS (
+                INSERT INTO job_archive
+                    (uid, type, data, result, errors, state, attempts, max_attempts, created, started, completed, archived)
+                SELECT uid, type, data, result, errors, state, attempts, max_attempts, created, started, completed, $4
+                FROM pruned
+                WHERE $5
+            ), attempts AS (
+                DELETE FROM job_attempt
+                WHERE job_uid IN (SELECT uid FROM pruned) AND NOT $5
+            )
+            SELECT count(*) FROM pruned
+        "})
+            .bind(JobState::Completed)
+            .bind(retention.completed.cutoff(now)?)
+            .bind(retention.completed.max_count.map(|x| x as i64))
+            .bind(now)
+            .bind(retention.archive)
+            .bind(&job_types)
+            .fetch_one(&self.pool).await?;
+
+        let failed: i64 = sqlx::query_scalar(indoc!{"
+            WITH pruned AS (
+                DELETE FROM job_dead_letter
+                WHERE type = ANY($6) AND (
+                    dead_lettered < $1
+                    OR id NOT IN (
+                        SELECT id
+                        FROM job_dead_letter
+                        WHERE type = ANY($6)
+                        ORDER BY dead_lettered DESC, id DESC
+                        LIMIT $2
+                    )
+                )
+                RETURNING *
+            ), archived AS (
+                INSERT INTO job_archive
+                    (uid, type, data, result, errors, state, attempts, max_attempts, created, completed, archived)
+                SELECT uid, type, data, errors -> -1, errors, $3, attempts, max_attempts, created, dead_lettered, $4
+                FROM pruned
+                WHERE $5
+            ), attempts AS (
+                DELETE FROM job_attempt
+                WHERE job_uid IN (SELECT uid FROM pruned) AND NOT $5
+            )
+            SELECT count(*) FROM pruned
+        "})
+            .bind(retention.failed.cutoff(now)?)
+            .bind(retention.failed.max_count.map(|x| x as i64))
+            .bind(JobState::Failed)
+            .bind(now)
+            .bind(retention.archive)
+            .bind(&job_types)
+            .fetch_one(&self.pool).await?;
+
+        Ok((completed + failed) as u64)
+    }
+
+    async fn acquire_rate_limit(
+        &mut self,
+        key: &str,
+        rate_limit: &RateLimit,
+    ) -> Result<Option<Duration>, StorageError> {
+        let now = Utc::now();
+        let (window_start, window_end) = rate_limit.window(now);
+
+        // No row comes back when the current window is already full
+        let acquired = sqlx::query(indoc!{"
+            INSERT INTO job_rate_limit AS rate_limit
+                (key, window_start, count)
+            VALUES ($1, $2, 1)
+            ON CONFLICT (key) DO UPDATE
+            SET
+                window_start = $2,
+                count = CASE WHEN rate_limit.window_start = $2 THEN rate_limit.count + 1 ELSE 1 END
+            WHERE rate_limit.window_start <> $2 OR rate_limit.count < $3
+            RETURNING count
+        "})
+            .bind(key)
+            .bind(window_start)
+            .bind(rate_limit.limit as i32)
+            .fetch_optional(&self.pool).await?;
+
+        match acquired {
+            Some(_) => Ok(None),
+            None => Ok(Some((window_end - now).to_std().unwrap_or_default())),
+        }
+    }
 }
 
 #[derive(sqlx::FromRow)]
@@ -143,6 +755,16 @@ pub struct DbJob {
     created: DateTime<Utc>,
     started: Option<DateTime<Utc>>,
     completed: Option<DateTime<Utc>>,
+    attempts: i32,
+    max_attempts: i32,
+    errors: Value,
+    trace_context: Value,
+    concurrency_key: Option<String>,
+    concurrency_limit: i32,
+    progress: Option<f32>,
+    progress_message: Option<String>,
+    checkpoint: Option<Value>,
+    version: i32,
 }
 
 impl DbJob {
@@ -150,13 +772,8 @@ impl DbJob {
     where
         Box<J>: DeserializeOwned,
     {
-        let metadata = JobMetadata {
-            uid: Ulid::from(self.uid),
-            state: self.state,
-            result: self.result.map(serde_json::from_value).transpose()?,
-        };
-
-        let job: Box<J> = serde_json::from_value(self.data)?;
+        let job: Box<J> = deserialize_job(self.data.clone(), self.version as u32)?;
+        let metadata = self.into_job_metadata()?;
 
         Ok(JobInfo { metadata, job })
     }
@@ -164,19 +781,103 @@ impl DbJob {
     pub fn into_job_metadata(self) -> Result<JobMetadata, serde_json::Error> {
         Ok(JobMetadata {
             uid: Ulid::from(self.uid),
+            job_type: self.job_type,
             state: self.state,
             result: self.result.map(serde_json::from_value).transpose()?,
+            attempts: self.attempts as u32,
+            max_attempts: self.max_attempts as u32,
+            errors: serde_json::from_value(self.errors)?,
+            created: self.created,
+            started: self.started,
+            completed: self.completed,
+            trace_context: serde_json::from_value(self.trace_context)?,
+            concurrency_key: self.concurrency_key,
+            concurrency_limit: self.concurrency_limit as u32,
+            progress: self.progress.map(|percent| JobProgress {
+                percent,
+                message: self.progress_message,
+            }),
+            checkpoint: self.checkpoint,
+            version: self.version as u32,
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+#[allow(dead_code)] // NOTE - Allow unused attributes for now
+pub struct DbDeadLetter {
+    id: i32,
+    uid: Uuid,
+    #[sqlx(rename = "type")]
+    job_type: String,
+    data: Value,
+    errors: Value,
+    attempts: i32,
+    max_attempts: i32,
+    created: DateTime<Utc>,
+    dead_lettered: DateTime<Utc>,
+    concurrency_key: Option<String>,
+    concurrency_limit: i32,
+    version: i32,
+}
+
+impl DbDeadLetter {
+    pub fn into_dead_letter(self) -> Result<DeadLetter, serde_json::Error> {
+        Ok(DeadLetter {
+            uid: Ulid::from(self.uid),
+            job_type: self.job_type,
+            data: self.data,
+            errors: serde_json::from_value(self.errors)?,
+            attempts: self.attempts as u32,
+            max_attempts: self.max_attempts as u32,
+            created: self.created,
+            dead_lettered: self.dead_lettered,
+            concurrency_key: self.concurrency_key,
+            concurrency_limit: self.concurrency_limit as u32,
+            version: self.version as u32,
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+#[allow(dead_code)] // NOTE - Allow unused attributes for now
+pub struct DbJobAttempt {
+    id: i32,
+    job_uid: Uuid,
+    attempt: i32,
+    started: DateTime<Utc>,
+    finished: DateTime<Utc>,
+    worker_id: String,
+    error: Option<Value>,
+}
+
+impl DbJobAttempt {
+    pub fn into_job_attempt(self) -> Result<JobAttempt, serde_json::Error> {
+        Ok(JobAttempt {
+            job_uid: Ulid::from(self.job_uid),
+            attempt: self.attempt as u32,
+            started: self.started,
+            finished: self.finished,
+            worker_id: self.worker_id,
+            error: self.error.map(serde_json::from_value).transpose()?,
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use async_trait::async_trait;
-    use sqlx::{Pool, Postgres};
+    use indoc::indoc;
+    use sqlx::{Pool, Postgres, types::Uuid};
+    use ulid::Ulid;
 
-    use super::PostgresStorageProvider;
-    use crate::{job, job_type, Job, StorageProvider, storage::JobState};
+    use super::{PostgresStorageProvider, CONCURRENCY_KEY_LOCK};
+    use crate::{
+        job, job_migration, job_type, Job, JobContext, JobRunError, StorageProvider,
+        storage::{JobAttempt, JobFilter, JobProgress, JobState, RateLimit, Retention, RetentionPolicy},
+    };
 
     #[job_type]
     struct MockJobType {}
@@ -190,7 +891,9 @@ mod tests {
     #[async_trait]
     impl Job for MockJob {
         type JobTypeData = MockJobType;
-        async fn run(&self, _: &Self::JobTypeData) {}
+        async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            Ok(())
+        }
     }
 
     #[job(MockJobType)]
@@ -202,7 +905,27 @@ mod tests {
     #[async_trait]
     impl Job for MockJob2 {
         type JobTypeData = MockJobType;
-        async fn run(&self, _: &Self::JobTypeData) {}
+        async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            Ok(())
+        }
+    }
+
+    #[job(MockJobType)]
+    #[derive(PartialEq)]
+    struct MockRetryJob {
+        msg: String,
+    }
+
+    #[async_trait]
+    impl Job for MockRetryJob {
+        type JobTypeData = MockJobType;
+        async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            Ok(())
+        }
+
+        fn max_attempts(&self) -> u32 {
+            2
+        }
     }
 
     #[sqlx::test]
@@ -219,6 +942,100 @@ mod tests {
         assert_eq!(*storage.pull().await.unwrap().job.into_any().downcast::<MockJob2>().unwrap(), job2);
     }
 
+    #[job(MockJobType)]
+    #[derive(PartialEq)]
+    struct MockTenantJob {
+        tenant: String,
+        limit: u32,
+    }
+
+    #[async_trait]
+    impl Job for MockTenantJob {
+        type JobTypeData = MockJobType;
+        async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            Ok(())
+        }
+
+        fn concurrency_key(&self) -> Option<String> {
+            Some(self.tenant.clone())
+        }
+
+        fn concurrency_limit(&self) -> u32 {
+            self.limit
+        }
+    }
+
+    #[job(MockJobType, version = 3)]
+    #[derive(PartialEq)]
+    struct VersionedJob {
+        messages: Vec<String>,
+    }
+
+    #[async_trait]
+    impl Job for VersionedJob {
+        type JobTypeData = MockJobType;
+        async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            Ok(())
+        }
+    }
+
+    #[job_migration(VersionedJob, from = 1)]
+    fn rename_msg(mut data: serde_json::Value) -> Result<serde_json::Value, serde_json::Error> {
+        data["message"] = data["msg"].take();
+        Ok(data)
+    }
+
+    #[job_migration(VersionedJob, from = 2)]
+    fn split_message(data: serde_json::Value) -> Result<serde_json::Value, serde_json::Error> {
+        Ok(serde_json::json!({ "messages": [data["message"]] }))
+    }
+
+    #[job_type(name = "renamed_type", alias = "OldJobType")]
+    struct RenamedJobType {}
+
+    #[job(RenamedJobType, name = "renamed_job", alias = "OldJob")]
+    #[derive(PartialEq)]
+    struct RenamedJob {
+        msg: String,
+    }
+
+    #[async_trait]
+    impl Job for RenamedJob {
+        type JobTypeData = RenamedJobType;
+        async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            Ok(())
+        }
+    }
+
+    #[job_type]
+    struct OtherJobType {}
+
+    #[job(OtherJobType)]
+    #[derive(PartialEq)]
+    struct OtherJob {
+        msg: String,
+    }
+
+    #[async_trait]
+    impl Job for OtherJob {
+        type JobTypeData = OtherJobType;
+        async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+            Ok(())
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_pull_by_type(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn.clone());
+        let mut other_storage = PostgresStorageProvider::<dyn OtherJobTypeMarker>::new(conn);
+
+        let other_job = OtherJob { msg: "a".to_string() };
+        other_storage.push(&other_job).await.unwrap();
+
+        assert!(storage.pull_many(10).await.unwrap().is_empty());
+        assert_eq!(*other_storage.pull().await.unwrap().job.into_any().downcast::<OtherJob>().unwrap(), other_job);
+    }
+
     #[sqlx::test]
     async fn test_set_job_status(conn: Pool<Postgres>) {
         let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn);
@@ -233,4 +1050,525 @@ mod tests {
         let job_meta = storage.get_job(job_meta.uid).await.unwrap();
         assert_eq!(job_meta.state, JobState::Completed);
     }
+
+    #[sqlx::test]
+    async fn test_push_many(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn);
+
+        let job1 = MockJob { msg: "a".to_string() };
+        let job2 = MockJob2 { msg2: "b".to_string() };
+        let job3 = MockJob { msg: "c".to_string() };
+
+        let jobs_meta = storage.push_many(&[&job1, &job2, &job3]).await.unwrap();
+        assert_eq!(jobs_meta.len(), 3);
+        assert!(jobs_meta.iter().all(|meta| meta.state == JobState::NotStarted));
+
+        assert_eq!(*storage.pull().await.unwrap().job.into_any().downcast::<MockJob>().unwrap(), job1);
+        assert_eq!(*storage.pull().await.unwrap().job.into_any().downcast::<MockJob2>().unwrap(), job2);
+        assert_eq!(*storage.pull().await.unwrap().job.into_any().downcast::<MockJob>().unwrap(), job3);
+    }
+
+    #[sqlx::test]
+    async fn test_push_in_tx(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn.clone());
+
+        let job1 = MockJob { msg: "a".to_string() };
+        let job2 = MockJob { msg: "b".to_string() };
+
+        let mut tx = conn.begin().await.unwrap();
+        let rolled_back_meta = storage.push_in_tx(&mut tx, &job1).await.unwrap();
+        tx.rollback().await.unwrap();
+
+        let mut tx = conn.begin().await.unwrap();
+        storage.push_in_tx(&mut tx, &job2).await.unwrap();
+        assert!(storage.try_pull().await.unwrap().is_none());
+        tx.commit().await.unwrap();
+
+        assert!(storage.get_job(rolled_back_meta.uid).await.is_err());
+        assert_eq!(*storage.pull().await.unwrap().job.into_any().downcast::<MockJob>().unwrap(), job2);
+    }
+
+    #[sqlx::test]
+    async fn test_pull_many_release(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn);
+
+        let job1 = MockJob { msg: "a".to_string() };
+        let job2 = MockJob2 { msg2: "b".to_string() };
+        let job3 = MockJob { msg: "c".to_string() };
+        storage.push_many(&[&job1, &job2, &job3]).await.unwrap();
+
+        let pulled = storage.pull_many(2).await.unwrap();
+        assert_eq!(pulled.len(), 2);
+        assert!(pulled.iter().all(|job_info| job_info.metadata.state == JobState::Running));
+
+        storage.release(&[pulled[1].metadata.uid]).await.unwrap();
+        let job_meta = storage.get_job(pulled[1].metadata.uid).await.unwrap();
+        assert_eq!(job_meta.state, JobState::NotStarted);
+
+        let pulled = storage.pull_many(5).await.unwrap();
+        assert_eq!(pulled.len(), 2);
+        assert!(storage.pull_many(5).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_dead_letter(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn);
+        let error = JobRunError::new("failed");
+
+        let job_meta = storage.push(&MockRetryJob { msg: "a".to_string() }).await.unwrap();
+        assert_eq!(job_meta.max_attempts, 2);
+
+        storage.pull().await.unwrap();
+        let job_meta = storage.set_job_result(job_meta.uid, Err(error.clone())).await.unwrap();
+        assert_eq!(job_meta.state, JobState::NotStarted);
+
+        storage.pull().await.unwrap();
+        let job_meta = storage.set_job_result(job_meta.uid, Err(error.clone())).await.unwrap();
+        assert_eq!(job_meta.state, JobState::Failed);
+        assert!(storage.try_pull().await.unwrap().is_none());
+
+        let dead_letters = storage.list_dead_letters().await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].errors.len(), 2);
+        assert_eq!(dead_letters[0].attempts, 2);
+        assert_eq!(storage.get_job(job_meta.uid).await.unwrap().state, JobState::Failed);
+
+        let edited_job = MockRetryJob { msg: "b".to_string() };
+        storage.requeue_dead_letter(job_meta.uid, Some(&edited_job)).await.unwrap();
+        assert!(storage.list_dead_letters().await.unwrap().is_empty());
+
+        let job_info = storage.pull().await.unwrap();
+        assert_eq!(job_info.metadata.attempts, 1);
+        assert_eq!(job_info.metadata.errors.len(), 2);
+        assert_eq!(*job_info.job.into_any().downcast::<MockRetryJob>().unwrap(), edited_job);
+
+        storage.set_job_result(job_meta.uid, Err(error.clone())).await.unwrap();
+        storage.pull().await.unwrap();
+        storage.set_job_result(job_meta.uid, Err(error)).await.unwrap();
+
+        assert_eq!(storage.purge_dead_letters(&[job_meta.uid]).await.unwrap(), 1);
+        assert!(storage.get_job(job_meta.uid).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_prune(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn.clone());
+        let error = JobRunError::new("failed");
+
+        let jobs = [
+            MockJob { msg: "a".to_string() },
+            MockJob { msg: "b".to_string() },
+            MockJob { msg: "c".to_string() },
+        ];
+        let jobs_meta = storage.push_many(&[&jobs[0], &jobs[1], &jobs[2]]).await.unwrap();
+        for job_meta in &jobs_meta {
+            storage.pull().await.unwrap();
+            storage.set_job_result(job_meta.uid, Err(error.clone())).await.unwrap();
+        }
+
+        let jobs_meta = storage.push_many(&[&jobs[0], &jobs[1], &jobs[2]]).await.unwrap();
+        for job_meta in &jobs_meta {
+            storage.pull().await.unwrap();
+            storage.set_job_result(job_meta.uid, Ok(())).await.unwrap();
+        }
+
+        let retention = RetentionPolicy {
+            completed: Retention { max_age: None, max_count: Some(1) },
+            failed: Retention { max_age: Some(Duration::ZERO), max_count: None },
+            archive: true,
+            ..Default::default()
+        };
+        assert_eq!(storage.prune(&retention).await.unwrap(), 5);
+        assert!(storage.list_dead_letters().await.unwrap().is_empty());
+        assert!(storage.get_job(jobs_meta[1].uid).await.is_err());
+        assert_eq!(storage.get_job(jobs_meta[2].uid).await.unwrap().state, JobState::Completed);
+
+        let archived: i64 = sqlx::query_scalar("SELECT count(*) FROM job_archive")
+            .fetch_one(&conn).await.unwrap();
+        assert_eq!(archived, 5);
+
+        assert_eq!(storage.prune(&retention).await.unwrap(), 0);
+    }
+
+    #[sqlx::test]
+    async fn test_prune_by_type(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn.clone());
+        let mut other_storage = PostgresStorageProvider::<dyn OtherJobTypeMarker>::new(conn);
+        let error = JobRunError::new("failed");
+
+        let other_jobs = [OtherJob { msg: "a".to_string() }, OtherJob { msg: "b".to_string() }];
+        let other_meta = other_storage.push_many(&[&other_jobs[0], &other_jobs[1]]).await.unwrap();
+        other_storage.pull().await.unwrap();
+        other_storage.set_job_result(other_meta[0].uid, Ok(())).await.unwrap();
+        other_storage.pull().await.unwrap();
+        other_storage.set_job_result(other_meta[1].uid, Err(error.clone())).await.unwrap();
+
+        let jobs = [MockJob { msg: "a".to_string() }, MockJob { msg: "b".to_string() }];
+        let jobs_meta = storage.push_many(&[&jobs[0], &jobs[1]]).await.unwrap();
+        storage.pull().await.unwrap();
+        storage.set_job_result(jobs_meta[0].uid, Ok(())).await.unwrap();
+        storage.pull().await.unwrap();
+        storage.set_job_result(jobs_meta[1].uid, Err(error)).await.unwrap();
+
+        let retention = RetentionPolicy {
+            completed: Retention { max_age: Some(Duration::ZERO), max_count: Some(0) },
+            failed: Retention { max_age: Some(Duration::ZERO), max_count: Some(0) },
+            archive: true,
+            ..Default::default()
+        };
+        assert_eq!(storage.prune(&retention).await.unwrap(), 2);
+        assert!(storage.get_job(jobs_meta[0].uid).await.is_err());
+        assert!(storage.get_dead_letter(jobs_meta[1].uid).await.is_err());
+
+        assert_eq!(other_storage.get_job(other_meta[0].uid).await.unwrap().state, JobState::Completed);
+        assert_eq!(other_storage.get_dead_letter(other_meta[1].uid).await.unwrap().uid, other_meta[1].uid);
+    }
+
+    #[sqlx::test]
+    async fn test_list_jobs(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn);
+        let error = JobRunError::new("failed");
+
+        let job1 = MockJob { msg: "a".to_string() };
+        let job2 = MockJob2 { msg2: "b".to_string() };
+        let jobs_meta = storage.push_many(&[&job1, &job2, &job1, &job2]).await.unwrap();
+
+        storage.pull().await.unwrap();
+        storage.set_job_result(jobs_meta[0].uid, Err(error)).await.unwrap();
+        storage.pull().await.unwrap();
+        storage.set_job_result(jobs_meta[1].uid, Ok(())).await.unwrap();
+
+        let page = storage.list_jobs(&JobFilter::default()).await.unwrap();
+        assert_eq!(page.jobs.len(), 4);
+        assert!(page.next_cursor.is_none());
+
+        let failed = storage.list_jobs(&JobFilter {
+            states: vec![JobState::Failed],
+            has_error: Some(true),
+            ..Default::default()
+        }).await.unwrap();
+        assert_eq!(failed.jobs.len(), 1);
+        assert_eq!(failed.jobs[0].uid, jobs_meta[0].uid);
+        assert_eq!(failed.jobs[0].job_type, "MockJobType");
+
+        let filter = JobFilter {
+            states: vec![JobState::NotStarted, JobState::Completed],
+            limit: 1,
+            ..Default::default()
+        };
+        let first_page = storage.list_jobs(&filter).await.unwrap();
+        assert_eq!(first_page.jobs.len(), 1);
+        assert!(first_page.next_cursor.is_some());
+
+        let mut listed = first_page.jobs;
+        let mut cursor = first_page.next_cursor;
+        while let Some(after) = cursor {
+            let page = storage.list_jobs(&JobFilter { after: Some(after), ..filter.clone() }).await.unwrap();
+            listed.extend(page.jobs);
+            cursor = page.next_cursor;
+        }
+        let listed: Vec<_> = listed.into_iter().map(|job| job.uid).collect();
+        let mut expected = vec![jobs_meta[1].uid, jobs_meta[2].uid, jobs_meta[3].uid];
+        expected.sort();
+        assert_eq!(listed, expected);
+
+        let started = storage.list_jobs(&JobFilter {
+            started_after: Some(jobs_meta[0].created),
+            ..Default::default()
+        }).await.unwrap();
+        assert_eq!(started.jobs.len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn test_stats(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn);
+        let error = JobRunError::new("failed");
+
+        let job = MockJob { msg: "a".to_string() };
+        let jobs_meta = storage.push_many(&[&job, &job, &job, &job]).await.unwrap();
+
+        storage.pull().await.unwrap();
+        storage.set_job_result(jobs_meta[0].uid, Err(error)).await.unwrap();
+        storage.pull().await.unwrap();
+        storage.set_job_result(jobs_meta[1].uid, Ok(())).await.unwrap();
+        storage.pull().await.unwrap();
+
+        let stats = storage.stats(Duration::from_secs(60)).await.unwrap();
+        assert_eq!(stats.job_types.len(), 1);
+
+        let job_stats = &stats.job_types["MockJobType"];
+        assert_eq!(job_stats.counts[&JobState::Failed], 1);
+        assert_eq!(job_stats.counts[&JobState::Completed], 1);
+        assert_eq!(job_stats.counts[&JobState::Running], 1);
+        assert_eq!(job_stats.counts[&JobState::NotStarted], 1);
+        assert!(job_stats.oldest_pending_age.is_some());
+        assert!(job_stats.average_latency.is_some());
+        assert_eq!(job_stats.completed_in_window, 1);
+        assert_eq!(job_stats.failed_in_window, 1);
+        assert!((job_stats.throughput - 1.0 / 60.0).abs() < f64::EPSILON);
+    }
+
+    #[sqlx::test]
+    async fn test_rate_limit(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn.clone());
+        let mut other_storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn);
+        let rate_limit = RateLimit { limit: 2, period: Duration::from_secs(3600) };
+
+        assert_eq!(storage.acquire_rate_limit("a", &rate_limit).await.unwrap(), None);
+        assert_eq!(other_storage.acquire_rate_limit("a", &rate_limit).await.unwrap(), None);
+
+        let wait = storage.acquire_rate_limit("a", &rate_limit).await.unwrap().unwrap();
+        assert!(wait <= rate_limit.period);
+
+        assert_eq!(storage.acquire_rate_limit("b", &rate_limit).await.unwrap(), None);
+    }
+
+    #[sqlx::test]
+    async fn test_concurrency_key(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn);
+
+        let jobs_meta = storage.push_many(&[
+            &MockTenantJob { tenant: "a".to_string(), limit: 1 },
+            &MockTenantJob { tenant: "a".to_string(), limit: 1 },
+            &MockTenantJob { tenant: "b".to_string(), limit: 2 },
+            &MockTenantJob { tenant: "b".to_string(), limit: 2 },
+            &MockJob { msg: "c".to_string() },
+        ]).await.unwrap();
+        assert_eq!(jobs_meta[0].concurrency_key.as_deref(), Some("a"));
+
+        let pulled: Vec<_> = storage.pull_many(10).await.unwrap().into_iter()
+            .map(|job_info| job_info.metadata.uid)
+            .collect();
+        assert_eq!(pulled, vec![jobs_meta[0].uid, jobs_meta[2].uid, jobs_meta[3].uid, jobs_meta[4].uid]);
+        assert!(storage.pull_many(10).await.unwrap().is_empty());
+
+        storage.set_job_result(jobs_meta[0].uid, Ok(())).await.unwrap();
+        assert_eq!(storage.pull().await.unwrap().metadata.uid, jobs_meta[1].uid);
+    }
+
+    #[sqlx::test]
+    async fn test_concurrency_key_locks(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn.clone());
+
+        let jobs_meta = storage.push_many(&[
+            &MockTenantJob { tenant: "a".to_string(), limit: 1 },
+            &MockTenantJob { tenant: "b".to_string(), limit: 1 },
+            &MockJob { msg: "c".to_string() },
+        ]).await.unwrap();
+
+        // Another executor in the middle of pulling key "a"
+        let mut tx = conn.begin().await.unwrap();
+        sqlx::query("SELECT pg_advisory_xact_lock($1, hashtext('a'))")
+            .bind(CONCURRENCY_KEY_LOCK)
+            .execute(&mut tx).await.unwrap();
+
+        let pulled: Vec<_> = storage.pull_many(10).await.unwrap().into_iter()
+            .map(|job_info| job_info.metadata.uid)
+            .collect();
+        assert_eq!(pulled, vec![jobs_meta[1].uid, jobs_meta[2].uid]);
+
+        tx.rollback().await.unwrap();
+        assert_eq!(storage.pull().await.unwrap().metadata.uid, jobs_meta[0].uid);
+    }
+
+    #[sqlx::test]
+    async fn test_lease(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn.clone())
+            .with_lease(Duration::from_secs(3600));
+        let mut expired_storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn)
+            .with_lease(Duration::ZERO);
+
+        let job_meta = storage.push(&MockTenantJob { tenant: "a".to_string(), limit: 1 }).await.unwrap();
+        storage.pull().await.unwrap();
+        assert!(expired_storage.try_pull().await.unwrap().is_none());
+
+        // The executor running the job crashes after saving a checkpoint, which renews the lease
+        let checkpoint = serde_json::json!({ "step": 1 });
+        expired_storage.save_checkpoint(job_meta.uid, &checkpoint).await.unwrap();
+
+        let job_info = storage.try_pull().await.unwrap().expect("Expired job wasn't pulled again");
+        assert_eq!(job_info.metadata.uid, job_meta.uid);
+        assert_eq!(job_info.metadata.attempts, 2);
+        assert_eq!(job_info.metadata.checkpoint, Some(checkpoint));
+        assert!(storage.try_pull().await.unwrap().is_none());
+    }
+
+    #[sqlx::test]
+    async fn test_set_progress(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn);
+
+        let job_meta = storage.push(&MockJob { msg: "a".to_string() }).await.unwrap();
+        storage.pull().await.unwrap();
+
+        let progress = JobProgress { percent: 50.0, message: Some("Halfway".to_string()) };
+        storage.set_progress(job_meta.uid, &progress).await.unwrap();
+        assert_eq!(storage.get_job(job_meta.uid).await.unwrap().progress, Some(progress.clone()));
+
+        storage.set_job_result(job_meta.uid, Ok(())).await.unwrap();
+        let page = storage.list_jobs(&JobFilter::default()).await.unwrap();
+        assert_eq!(page.jobs[0].progress, Some(progress));
+    }
+
+    #[sqlx::test]
+    async fn test_save_checkpoint(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn);
+
+        let job_meta = storage.push(&MockJob { msg: "a".to_string() }).await.unwrap();
+        assert_eq!(storage.pull().await.unwrap().metadata.checkpoint, None);
+
+        let checkpoint = serde_json::json!({ "offset": 10 });
+        storage.save_checkpoint(job_meta.uid, &checkpoint).await.unwrap();
+        storage.release(&[job_meta.uid]).await.unwrap();
+
+        assert_eq!(storage.pull().await.unwrap().metadata.checkpoint, Some(checkpoint));
+    }
+
+    #[sqlx::test]
+    async fn test_renamed_jobs(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn RenamedJobTypeMarker>::new(conn.clone());
+
+        sqlx::query(indoc!{"
+            INSERT INTO job_queue (uid, type, data, created)
+            VALUES ($1, 'OldJobType', '{\"type\": \"OldJob\", \"msg\": \"a\"}', now())
+        "})
+            .bind(Uuid::from(Ulid::new()))
+            .execute(&conn).await.unwrap();
+
+        let job_info = storage.pull().await.unwrap();
+        assert_eq!(job_info.metadata.job_type, "OldJobType");
+        assert_eq!(*job_info.job.into_any().downcast::<RenamedJob>().unwrap(), RenamedJob { msg: "a".to_string() });
+
+        let job_meta = storage.push(&RenamedJob { msg: "b".to_string() }).await.unwrap();
+        assert_eq!(job_meta.job_type, "renamed_type");
+
+        let data: serde_json::Value = sqlx::query_scalar("SELECT data FROM job_queue WHERE uid = $1")
+            .bind(Uuid::from(job_meta.uid))
+            .fetch_one(&conn).await.unwrap();
+        assert_eq!(data["type"], "renamed_job");
+    }
+
+    #[sqlx::test]
+    async fn test_job_versions(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn.clone());
+
+        sqlx::query(indoc!{"
+            INSERT INTO job_queue (uid, type, data, created, version)
+            VALUES ($1, 'MockJobType', '{\"type\": \"VersionedJob\", \"msg\": \"a\"}', now(), 1)
+        "})
+            .bind(Uuid::from(Ulid::new()))
+            .execute(&conn).await.unwrap();
+
+        let job_info = storage.pull().await.unwrap();
+        assert_eq!(job_info.metadata.version, 1);
+        let job = job_info.job.into_any().downcast::<VersionedJob>().unwrap();
+        assert_eq!(*job, VersionedJob { messages: vec!["a".to_string()] });
+
+        let job_meta = storage.push(&*job).await.unwrap();
+        assert_eq!(job_meta.version, 3);
+        let job_info = storage.pull().await.unwrap();
+        assert_eq!(*job_info.job.into_any().downcast::<VersionedJob>().unwrap(), *job);
+    }
+
+    #[sqlx::test]
+    async fn test_poisoned_jobs(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn.clone());
+
+        let uid = Ulid::new();
+        sqlx::query(indoc!{"
+            INSERT INTO job_queue (uid, type, data, created)
+            VALUES ($1, 'MockJobType', '{\"type\": \"MockJob\", \"message\": \"a\"}', now())
+        "})
+            .bind(Uuid::from(uid))
+            .execute(&conn).await.unwrap();
+        let job = MockJob { msg: "b".to_string() };
+        storage.push(&job).await.unwrap();
+
+        let jobs = storage.pull_many(2).await.unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(*jobs.into_iter().next().unwrap().job.into_any().downcast::<MockJob>().unwrap(), job);
+
+        let job_meta = storage.get_job(uid).await.unwrap();
+        assert_eq!(job_meta.state, JobState::Poisoned);
+        assert_eq!(job_meta.result.unwrap().code.as_deref(), Some(JobRunError::DESERIALIZATION_CODE));
+        assert_eq!(job_meta.errors.len(), 1);
+        assert!(storage.pull_many(1).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_structured_errors(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn.clone());
+
+        let error = JobRunError::from_error(&serde_json::from_str::<u32>("x").unwrap_err())
+            .with_code("invalid_input")
+            .with_cause("Bad request")
+            .with_details(&serde_json::json!({ "input": "x" })).unwrap()
+            .permanent();
+
+        let job_meta = storage.push(&MockRetryJob { msg: "a".to_string() }).await.unwrap();
+        storage.pull().await.unwrap();
+        let job_meta = storage.set_job_result(job_meta.uid, Err(error.clone())).await.unwrap();
+        assert_eq!(job_meta.state, JobState::Failed);
+        assert_eq!(job_meta.attempts, 1);
+
+        let dead_letter = storage.get_dead_letter(job_meta.uid).await.unwrap();
+        assert_eq!(dead_letter.errors, vec![error]);
+
+        let uid = Ulid::new();
+        sqlx::query(indoc!{"
+            INSERT INTO job_queue (uid, type, data, created, result, errors)
+            VALUES (
+                $1, 'MockJobType', '{\"type\": \"MockJob\", \"msg\": \"a\"}', now(),
+                '{\"TaskFailure\": {\"msg\": \"old\"}}', '[{\"TaskFailure\": {\"msg\": \"old\"}}]'
+            )
+        "})
+            .bind(Uuid::from(uid))
+            .execute(&conn).await.unwrap();
+
+        let job_meta = storage.get_job(uid).await.unwrap();
+        assert_eq!(job_meta.result, Some(JobRunError::new("old")));
+        assert_eq!(job_meta.errors, vec![JobRunError::new("old")]);
+    }
+
+    #[sqlx::test]
+    async fn test_attempts(conn: Pool<Postgres>) {
+        let mut storage = PostgresStorageProvider::<dyn MockJobTypeMarker>::new(conn);
+
+        let job_meta = storage.push(&MockJob { msg: "a".to_string() }).await.unwrap();
+        assert!(storage.list_attempts(job_meta.uid).await.unwrap().is_empty());
+
+        let now = chrono::Utc::now();
+        let attempts = vec![
+            JobAttempt {
+                job_uid: job_meta.uid,
+                attempt: 1,
+                started: now,
+                finished: now,
+                worker_id: "worker-1".to_string(),
+                error: Some(JobRunError::new("failed")),
+            },
+            JobAttempt {
+                job_uid: job_meta.uid,
+                attempt: 2,
+                started: now,
+                finished: now,
+                worker_id: "worker-2".to_string(),
+                error: None,
+            },
+        ];
+        for attempt in &attempts {
+            storage.record_attempt(attempt).await.unwrap();
+        }
+
+        let recorded = storage.list_attempts(job_meta.uid).await.unwrap();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].worker_id, "worker-1");
+        assert_eq!(recorded[0].error, attempts[0].error);
+        assert_eq!(recorded[1].attempt, 2);
+        assert_eq!(recorded[1].error, None);
+    }
+
+    #[sqlx::test]
+    async fn test_conformance(conn: Pool<Postgres>) {
+        crate::testing::run_all(|| PostgresStorageProvider::new(conn.clone())).await;
+    }
 }
@@ -0,0 +1,36 @@
+//! Tracing spans for job runs, linked to the trace that pushed the job through an OpenTelemetry
+//! propagator. Register one with `opentelemetry::global::set_text_map_propagator`, otherwise no
+//! context is carried over.
+
+use std::collections::HashMap;
+
+use opentelemetry::global;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::storage::JobMetadata;
+
+/// Serialize the current span's context so it can be stored along with a job
+pub(crate) fn current_context() -> HashMap<String, String> {
+    let mut trace_context = HashMap::new();
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut trace_context));
+    trace_context
+}
+
+/// Span covering a single attempt of a job, parented to the trace it was pushed from
+pub(crate) fn job_span(metadata: &JobMetadata) -> Span {
+    let span = tracing::info_span!(
+        "job",
+        job.uid = %metadata.uid,
+        job.job_type = %metadata.job_type,
+        job.attempt = metadata.attempts,
+    );
+
+    if !metadata.trace_context.is_empty() {
+        let parent = global::get_text_map_propagator(|propagator| propagator.extract(&metadata.trace_context));
+        span.set_parent(parent);
+    }
+
+    span
+}
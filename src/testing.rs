@@ -0,0 +1,453 @@
+//! Conformance tests for `StorageProvider` implementations, enabled with the `testing` feature.
+//! Every built-in provider passes them, run them against a new backend to check it behaves the
+//! same way:
+//!
+//! ```ignore
+//! #[tokio::test]
+//! async fn conformance() {
+//!     let storage_provider = MyStorageProvider::connect("...").await.unwrap();
+//!     ajobqueue::testing::run_all(|| storage_provider.clone()).await;
+//! }
+//! ```
+//!
+//! `FlakyStorageProvider` makes pulls from any storage fail on demand, to test executors against
+//! storage outages.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use serde_json::Value;
+use tokio::time::{self, Duration, Instant};
+use ulid::Ulid;
+
+use crate::storage::{
+    DeadLetter, JobAttempt, JobFilter, JobInfo, JobMetadata, JobPage, JobProgress, JobState,
+    QueueStats, RateLimit, Retention, RetentionPolicy,
+};
+use crate::{
+    job, job_type, Job, JobContext, JobRunError, JobTypeMarker, StorageError, StorageProvider,
+};
+
+#[job_type]
+pub struct ConformanceJobType {}
+
+#[job(ConformanceJobType)]
+#[derive(PartialEq)]
+pub struct ConformanceJob {
+    n: u32,
+    max_attempts: u32,
+    concurrency_key: Option<String>,
+}
+
+impl ConformanceJob {
+    fn new(n: u32) -> Self {
+        Self { n, max_attempts: 1, concurrency_key: None }
+    }
+}
+
+#[async_trait]
+impl Job for ConformanceJob {
+    type JobTypeData = ConformanceJobType;
+    async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+        Ok(())
+    }
+
+    fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    fn concurrency_key(&self) -> Option<String> {
+        self.concurrency_key.clone()
+    }
+}
+
+/// Leaves `n` out when it's pushed, so it can't be read back and is poisoned when pulled
+#[job(ConformanceJobType)]
+pub struct UnreadableConformanceJob {
+    #[serde(skip_serializing)]
+    pub n: u32,
+}
+
+#[async_trait]
+impl Job for UnreadableConformanceJob {
+    type JobTypeData = ConformanceJobType;
+    async fn run(&self, _: &Self::JobTypeData, _: &JobContext) -> Result<(), JobRunError> {
+        Ok(())
+    }
+}
+
+/// Run every check against the storage handed out by `storage`, which must give a new handle to
+/// the same, initially empty, storage on every call. Each check leaves no jobs waiting to be
+/// pulled behind.
+pub async fn run_all<S, F>(storage: F)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker> + 'static,
+    F: Fn() -> S,
+{
+    try_pull_without_jobs(storage()).await;
+    try_pull_in_push_order(storage()).await;
+    pull_with_timeout_gives_up(storage()).await;
+    pull_with_timeout_waits_for_push(storage(), storage()).await;
+    pull_waits_for_push(storage(), storage()).await;
+    jobs_move_through_states(storage()).await;
+    released_jobs_are_pulled_again(storage()).await;
+    failed_jobs_are_retried_then_dead_lettered(storage()).await;
+    concurrent_pulls_take_each_job_once(&storage).await;
+    concurrency_keys_hold_back_jobs(storage()).await;
+    rate_limits_are_enforced(storage()).await;
+    poisoned_jobs_arent_completed(storage()).await;
+}
+
+fn job_number(job: Box<dyn ConformanceJobTypeMarker>) -> u32 {
+    job.into_any().downcast::<ConformanceJob>().expect("Pulled a different job").n
+}
+
+pub async fn try_pull_without_jobs<S>(mut storage: S)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker>,
+{
+    assert!(storage.try_pull().await.unwrap().is_none());
+    assert!(storage.pull_many(5).await.unwrap().is_empty());
+}
+
+pub async fn try_pull_in_push_order<S>(mut storage: S)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker>,
+{
+    storage.push(&ConformanceJob::new(1)).await.unwrap();
+    storage.push_many(&[&ConformanceJob::new(2), &ConformanceJob::new(3)]).await.unwrap();
+
+    for n in 1..=3 {
+        let job_info = storage.try_pull().await.unwrap().expect("Job wasn't pulled");
+        assert_eq!(job_number(job_info.job), n);
+    }
+    assert!(storage.try_pull().await.unwrap().is_none());
+}
+
+pub async fn pull_with_timeout_gives_up<S>(mut storage: S)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker>,
+{
+    let started = Instant::now();
+    assert!(storage.pull_with_timeout(Duration::from_millis(100)).await.unwrap().is_none());
+    assert!(started.elapsed() >= Duration::from_millis(100));
+}
+
+pub async fn pull_with_timeout_waits_for_push<S>(mut storage: S, mut pusher: S)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker> + 'static,
+{
+    let push = tokio::spawn(async move {
+        time::sleep(Duration::from_millis(50)).await;
+        pusher.push(&ConformanceJob::new(1)).await.unwrap();
+    });
+
+    let job_info = storage.pull_with_timeout(Duration::from_secs(5)).await.unwrap()
+        .expect("Job wasn't pulled");
+    assert_eq!(job_number(job_info.job), 1);
+    push.await.unwrap();
+}
+
+pub async fn pull_waits_for_push<S>(mut storage: S, mut pusher: S)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker> + 'static,
+{
+    let push = tokio::spawn(async move {
+        time::sleep(Duration::from_millis(50)).await;
+        pusher.push(&ConformanceJob::new(1)).await.unwrap();
+    });
+
+    let job_info = time::timeout(Duration::from_secs(5), storage.pull()).await
+        .expect("Pull didn't wait for the job")
+        .unwrap();
+    assert_eq!(job_number(job_info.job), 1);
+    push.await.unwrap();
+}
+
+pub async fn jobs_move_through_states<S>(mut storage: S)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker>,
+{
+    let job_meta = storage.push(&ConformanceJob::new(1)).await.unwrap();
+    assert_eq!(job_meta.state, JobState::NotStarted);
+    assert_eq!(job_meta.attempts, 0);
+    assert_eq!(storage.get_job(job_meta.uid).await.unwrap().state, JobState::NotStarted);
+
+    let job_info = storage.try_pull().await.unwrap().expect("Job wasn't pulled");
+    assert_eq!(job_info.metadata.uid, job_meta.uid);
+    assert_eq!(job_info.metadata.state, JobState::Running);
+    assert_eq!(job_info.metadata.attempts, 1);
+    assert!(job_info.metadata.started.is_some());
+    assert_eq!(storage.get_job(job_meta.uid).await.unwrap().state, JobState::Running);
+
+    let job_meta = storage.set_job_result(job_meta.uid, Ok(())).await.unwrap();
+    assert_eq!(job_meta.state, JobState::Completed);
+    assert!(job_meta.completed.is_some());
+    assert_eq!(job_meta.result, None);
+    assert_eq!(storage.get_job(job_meta.uid).await.unwrap().state, JobState::Completed);
+}
+
+pub async fn released_jobs_are_pulled_again<S>(mut storage: S)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker>,
+{
+    let job_meta = storage.push(&ConformanceJob::new(1)).await.unwrap();
+    storage.try_pull().await.unwrap().expect("Job wasn't pulled");
+
+    storage.release(&[job_meta.uid]).await.unwrap();
+    let released = storage.get_job(job_meta.uid).await.unwrap();
+    assert_eq!(released.state, JobState::NotStarted);
+    assert_eq!(released.attempts, 0);
+
+    let job_info = storage.try_pull().await.unwrap().expect("Released job wasn't pulled");
+    assert_eq!(job_info.metadata.uid, job_meta.uid);
+    assert_eq!(job_info.metadata.attempts, 1);
+    storage.set_job_result(job_meta.uid, Ok(())).await.unwrap();
+}
+
+pub async fn failed_jobs_are_retried_then_dead_lettered<S>(mut storage: S)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker>,
+{
+    let job = ConformanceJob { max_attempts: 2, ..ConformanceJob::new(1) };
+    let job_meta = storage.push(&job).await.unwrap();
+    let error = JobRunError::new("failed");
+
+    storage.try_pull().await.unwrap().expect("Job wasn't pulled");
+    let retried = storage.set_job_result(job_meta.uid, Err(error.clone())).await.unwrap();
+    assert_eq!(retried.state, JobState::NotStarted);
+    assert_eq!(retried.result, Some(error.clone()));
+
+    let job_info = storage.try_pull().await.unwrap().expect("Retried job wasn't pulled");
+    assert_eq!(job_info.metadata.uid, job_meta.uid);
+    assert_eq!(job_info.metadata.attempts, 2);
+    storage.save_checkpoint(job_meta.uid, &serde_json::json!({ "step": 1 })).await.unwrap();
+    let failed = storage.set_job_result(job_meta.uid, Err(error.clone())).await.unwrap();
+    assert_eq!(failed.state, JobState::Failed);
+    assert!(storage.try_pull().await.unwrap().is_none());
+
+    let dead_letter = storage.get_dead_letter(job_meta.uid).await.unwrap();
+    assert_eq!(dead_letter.attempts, 2);
+    assert_eq!(dead_letter.errors, vec![error.clone(), error]);
+    assert_eq!(storage.get_job(job_meta.uid).await.unwrap().state, JobState::Failed);
+
+    let requeued = storage.requeue_dead_letter(job_meta.uid, None).await.unwrap();
+    assert_eq!(requeued.state, JobState::NotStarted);
+    assert_eq!(requeued.attempts, 0);
+    assert_eq!(requeued.checkpoint, None);
+    assert!(storage.get_dead_letter(job_meta.uid).await.is_err());
+
+    let job_info = storage.try_pull().await.unwrap().expect("Requeued job wasn't pulled");
+    assert_eq!(job_number(job_info.job), 1);
+    storage.set_job_result(job_meta.uid, Ok(())).await.unwrap();
+}
+
+pub async fn concurrent_pulls_take_each_job_once<S, F>(storage: &F)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker> + 'static,
+    F: Fn() -> S,
+{
+    let jobs: Vec<_> = (0..50).map(ConformanceJob::new).collect();
+    let jobs: Vec<_> = jobs.iter().map(|job| job as _).collect();
+    let pushed = storage().push_many(&jobs).await.unwrap();
+
+    let pullers: Vec<_> = (0..5)
+        .map(|_| {
+            let mut storage = storage();
+            tokio::spawn(async move {
+                let mut pulled = Vec::new();
+                while let Some(job_info) = storage.try_pull().await.unwrap() {
+                    pulled.push(job_info.metadata.uid);
+                }
+                pulled
+            })
+        })
+        .collect();
+
+    let mut pulled = Vec::new();
+    for puller in pullers {
+        pulled.extend(puller.await.unwrap());
+    }
+    assert_eq!(pulled.len(), pushed.len());
+    assert_eq!(
+        pulled.into_iter().collect::<HashSet<_>>(),
+        pushed.into_iter().map(|job_meta| job_meta.uid).collect::<HashSet<_>>(),
+    );
+}
+
+pub async fn concurrency_keys_hold_back_jobs<S>(mut storage: S)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker>,
+{
+    let keyed = |n, key: &str| ConformanceJob {
+        concurrency_key: Some(key.to_string()),
+        ..ConformanceJob::new(n)
+    };
+    let jobs_meta = storage.push_many(&[&keyed(1, "a"), &keyed(2, "a"), &keyed(3, "b")])
+        .await.unwrap();
+
+    let pulled: Vec<_> = storage.pull_many(3).await.unwrap().into_iter()
+        .map(|job_info| job_number(job_info.job))
+        .collect();
+    assert_eq!(pulled, vec![1, 3]);
+    assert!(storage.try_pull().await.unwrap().is_none());
+
+    storage.set_job_result(jobs_meta[0].uid, Ok(())).await.unwrap();
+    let job_info = storage.try_pull().await.unwrap().expect("Held back job wasn't pulled");
+    assert_eq!(job_number(job_info.job), 2);
+}
+
+pub async fn rate_limits_are_enforced<S>(mut storage: S)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker>,
+{
+    let rate_limit = RateLimit { limit: 2, period: Duration::from_secs(3600) };
+
+    assert_eq!(storage.acquire_rate_limit("conformance", &rate_limit).await.unwrap(), None);
+    assert_eq!(storage.acquire_rate_limit("conformance", &rate_limit).await.unwrap(), None);
+    let wait = storage.acquire_rate_limit("conformance", &rate_limit).await.unwrap()
+        .expect("Rate limit wasn't enforced");
+    assert!(wait <= rate_limit.period);
+
+    assert_eq!(storage.acquire_rate_limit("conformance:other", &rate_limit).await.unwrap(), None);
+}
+
+pub async fn poisoned_jobs_arent_completed<S>(mut storage: S)
+where
+    S: StorageProvider<dyn ConformanceJobTypeMarker>,
+{
+    let window = Duration::from_secs(3600);
+    let completed_in_window = |stats: QueueStats, job_type: &str| {
+        stats.job_types.get(job_type).map_or(0, |stats| stats.completed_in_window)
+    };
+
+    let job_meta = storage.push(&UnreadableConformanceJob { n: 1 }).await.unwrap();
+    let completed = completed_in_window(storage.stats(window).await.unwrap(), &job_meta.job_type);
+    assert!(storage.pull_many(5).await.unwrap().is_empty());
+    assert_eq!(storage.get_job(job_meta.uid).await.unwrap().state, JobState::Poisoned);
+
+    let stats = storage.stats(window).await.unwrap();
+    assert_eq!(stats.job_types[&job_meta.job_type].counts.get(&JobState::Poisoned), Some(&1));
+    assert_eq!(completed_in_window(stats, &job_meta.job_type), completed);
+
+    let retention = RetentionPolicy {
+        completed: Retention { max_age: Some(Duration::ZERO), max_count: Some(0) },
+        ..Default::default()
+    };
+    storage.prune(&retention).await.unwrap();
+    assert_eq!(storage.get_job(job_meta.uid).await.unwrap().state, JobState::Poisoned);
+}
+
+/// Wraps a storage provider and fails its next `pull_many` calls with the given errors, in order,
+/// to check how executors cope with storage failures
+pub struct FlakyStorageProvider<S> {
+    inner: S,
+    errors: Mutex<VecDeque<StorageError>>,
+}
+
+impl<S> FlakyStorageProvider<S> {
+    pub fn new(inner: S, errors: Vec<StorageError>) -> Self {
+        Self { inner, errors: Mutex::new(errors.into()) }
+    }
+}
+
+#[async_trait]
+impl<J, S> StorageProvider<J> for FlakyStorageProvider<S>
+where
+    J: JobTypeMarker + ?Sized,
+    S: StorageProvider<J>,
+{
+    async fn push(&mut self, job: &J) -> Result<JobMetadata, StorageError> {
+        self.inner.push(job).await
+    }
+
+    async fn push_many(&mut self, jobs: &[&J]) -> Result<Vec<JobMetadata>, StorageError> {
+        self.inner.push_many(jobs).await
+    }
+
+    async fn pull(&mut self) -> Result<JobInfo<J>, StorageError> {
+        self.inner.pull().await
+    }
+
+    async fn try_pull(&mut self) -> Result<Option<JobInfo<J>>, StorageError> {
+        self.inner.try_pull().await
+    }
+
+    async fn pull_with_timeout(&mut self, timeout: Duration) -> Result<Option<JobInfo<J>>, StorageError> {
+        self.inner.pull_with_timeout(timeout).await
+    }
+
+    async fn pull_many(&mut self, count: usize) -> Result<Vec<JobInfo<J>>, StorageError> {
+        if let Some(err) = self.errors.lock().unwrap().pop_front() {
+            return Err(err);
+        }
+        self.inner.pull_many(count).await
+    }
+
+    async fn release(&mut self, uids: &[Ulid]) -> Result<(), StorageError> {
+        self.inner.release(uids).await
+    }
+
+    async fn set_job_result(&mut self, uid: Ulid, job_result: Result<(), JobRunError>)
+        -> Result<JobMetadata, StorageError>
+    {
+        self.inner.set_job_result(uid, job_result).await
+    }
+
+    async fn set_progress(&mut self, uid: Ulid, progress: &JobProgress) -> Result<(), StorageError> {
+        self.inner.set_progress(uid, progress).await
+    }
+
+    async fn save_checkpoint(&mut self, uid: Ulid, checkpoint: &Value) -> Result<(), StorageError> {
+        self.inner.save_checkpoint(uid, checkpoint).await
+    }
+
+    async fn record_attempt(&mut self, attempt: &JobAttempt) -> Result<(), StorageError> {
+        self.inner.record_attempt(attempt).await
+    }
+
+    async fn list_attempts(&self, job_id: Ulid) -> Result<Vec<JobAttempt>, StorageError> {
+        self.inner.list_attempts(job_id).await
+    }
+
+    async fn get_job(&self, job_id: Ulid) -> Result<JobMetadata, StorageError> {
+        self.inner.get_job(job_id).await
+    }
+
+    async fn list_jobs(&self, filter: &JobFilter) -> Result<JobPage, StorageError> {
+        self.inner.list_jobs(filter).await
+    }
+
+    async fn stats(&self, window: Duration) -> Result<QueueStats, StorageError> {
+        self.inner.stats(window).await
+    }
+
+    async fn list_dead_letters(&self) -> Result<Vec<DeadLetter>, StorageError> {
+        self.inner.list_dead_letters().await
+    }
+
+    async fn get_dead_letter(&self, job_id: Ulid) -> Result<DeadLetter, StorageError> {
+        self.inner.get_dead_letter(job_id).await
+    }
+
+    async fn requeue_dead_letter(&mut self, job_id: Ulid, job: Option<&J>)
+        -> Result<JobMetadata, StorageError>
+    {
+        self.inner.requeue_dead_letter(job_id, job).await
+    }
+
+    async fn purge_dead_letters(&mut self, job_ids: &[Ulid]) -> Result<u64, StorageError> {
+        self.inner.purge_dead_letters(job_ids).await
+    }
+
+    async fn prune(&mut self, retention: &RetentionPolicy) -> Result<u64, StorageError> {
+        self.inner.prune(retention).await
+    }
+
+    async fn acquire_rate_limit(&mut self, key: &str, rate_limit: &RateLimit)
+        -> Result<Option<Duration>, StorageError>
+    {
+        self.inner.acquire_rate_limit(key, rate_limit).await
+    }
+}